@@ -0,0 +1,147 @@
+//! Tones and samples on the Web Audio clock. Cues are queued ahead of time,
+//! so that they go off on time however late the page gets round to noticing
+//! them, and called off again if plans change.
+use crate::engine::Cue;
+use crate::scheduler::{self, Planned};
+use crate::sound::{Sound, Tone};
+use crate::synth;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{AudioBuffer, AudioContext, AudioScheduledSourceNode, GainNode};
+
+pub struct Audio {
+    ctx: AudioContext,
+    master: GainNode,
+    queued: RefCell<Vec<(Planned, Vec<AudioScheduledSourceNode>)>>,
+    /// Decoded samples, by name. Filled in as they finish decoding.
+    samples: Rc<RefCell<BTreeMap<String, AudioBuffer>>>,
+}
+
+impl Audio {
+    pub fn new() -> Option<Self> {
+        let ctx = AudioContext::new().ok()?;
+        let master = ctx.create_gain().ok()?;
+        master.connect_with_audio_node(&ctx.destination()).ok()?;
+        Some(Self {
+            ctx,
+            master,
+            queued: RefCell::new(Vec::new()),
+            samples: Rc::new(RefCell::new(BTreeMap::new())),
+        })
+    }
+    pub fn set_volume(&self, volume: f32) {
+        self.master.gain().set_value(volume);
+    }
+    /// Decode an audio file, to play as the sample `name` once it's ready.
+    pub fn load(&self, name: String, bytes: &[u8]) {
+        let data = js_sys::Uint8Array::from(bytes).buffer();
+        let decoding = match self.ctx.decode_audio_data(&data) {
+            Ok(p) => JsFuture::from(p),
+            Err(e) => {
+                seed::error!("Failed to decode sample", name, e);
+                return;
+            }
+        };
+        let samples = Rc::clone(&self.samples);
+        spawn_local(async move {
+            match decoding.await {
+                Ok(buffer) => {
+                    samples.borrow_mut().insert(name, buffer.unchecked_into());
+                }
+                Err(e) => seed::error!("Failed to decode sample", name, e),
+            }
+        });
+    }
+    pub fn unload(&self, name: &str) {
+        self.samples.borrow_mut().remove(name);
+    }
+    /// One tone, at `at` on the audio clock.
+    fn tone(&self, at: f64, tone: &Tone) -> Vec<AudioScheduledSourceNode> {
+        synth::play(
+            &self.ctx,
+            &self.master,
+            tone.instrument,
+            f64::from(tone.frequency),
+            at,
+            f64::from(tone.millis) / 1000.,
+        )
+        .unwrap_or_default()
+    }
+    fn sample(&self, at: f64, buffer: &AudioBuffer) -> Option<AudioScheduledSourceNode> {
+        let source = self.ctx.create_buffer_source().ok()?;
+        source.set_buffer(Some(buffer));
+        source.connect_with_audio_node(&self.master).ok()?;
+        source.start_with_when(at).ok()?;
+        Some(source.into())
+    }
+    /// The sample if we have it, or else the tones one after another,
+    /// starting `delay` seconds from now.
+    fn start(&self, delay: f64, sound: &Sound) -> Vec<AudioScheduledSourceNode> {
+        let mut at = self.ctx.current_time() + delay.max(0.);
+        let samples = self.samples.borrow();
+        if let Some(buffer) = sound.sample.as_ref().and_then(|s| samples.get(s)) {
+            return self.sample(at, buffer).into_iter().collect();
+        }
+        let mut nodes = Vec::new();
+        for t in &sound.tones {
+            if t.frequency > 0 {
+                nodes.extend(self.tone(at, t));
+            }
+            at += f64::from(t.millis) / 1000.;
+        }
+        nodes
+    }
+    pub fn play(&self, sound: &Sound) {
+        self.start(0., sound);
+    }
+    /// How long `sound` takes to play, in seconds.
+    pub fn duration(&self, sound: &Sound) -> f64 {
+        let samples = self.samples.borrow();
+        match sound.sample.as_ref().and_then(|s| samples.get(s)) {
+            Some(buffer) => buffer.duration(),
+            None => sound
+                .tones
+                .iter()
+                .map(|t| f64::from(t.millis) / 1000.)
+                .sum(),
+        }
+    }
+    /// Line up the planned cues, calling off any queued ones which aren't
+    /// in the plan any more. `now` is in millis since the epoch.
+    pub fn schedule(&self, plan: Vec<(Planned, Sound)>, now: i64) {
+        let mut queued = self.queued.borrow_mut();
+        let planned: Vec<_> = plan.iter().map(|p| p.0).collect();
+        let already: Vec<_> = queued.iter().map(|q| q.0).collect();
+        let (cancel, add) = scheduler::reconcile(&already, &planned, now);
+        queued.retain(|(p, nodes)| {
+            if cancel.contains(p) {
+                for n in nodes {
+                    n.stop().ok();
+                }
+            }
+            !cancel.contains(p) && !scheduler::stale(p, now)
+        });
+        for (p, sound) in plan {
+            if add.contains(&p) {
+                let nodes = self.start((p.at - now) as f64 / 1000., &sound);
+                queued.push((p, nodes));
+            }
+        }
+    }
+    /// Whether `cue`, just noticed, was queued ahead and so has played
+    /// already.
+    pub fn take(&self, cue: Cue, now: i64) -> bool {
+        let mut queued = self.queued.borrow_mut();
+        let already: Vec<_> = queued.iter().map(|q| q.0).collect();
+        match scheduler::find_due(&already, cue, now) {
+            Some(i) => {
+                queued.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Circuit classes: stations each with their own exercise, and groups moving
+//! round them, one station on after each go of work and rest.
+use crate::workout::{FlatStatus, Kind};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Circuit {
+    /// The exercise at each station.
+    pub stations: Vec<String>,
+    /// Group `g` starts at station `g`.
+    pub groups: Vec<String>,
+}
+
+/// A comma separated list, as typed in.
+pub fn parse_list(text: &str) -> Vec<String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn is_work(item: &FlatStatus) -> bool {
+    item.kind() == Kind::Work
+}
+
+/// How many times the groups have moved on by item `ix`: once for each
+/// piece of work finished, as they move at the end of the rest after it.
+/// Warming up and cooling down are done together, and don't count.
+pub fn round(items: &[FlatStatus], ix: usize) -> usize {
+    let work = items.iter().take(ix + 1).filter(|i| is_work(i)).count();
+    work.saturating_sub(1)
+}
+
+/// How many rounds there are in all: one for each piece of work.
+pub fn rounds(items: &[FlatStatus]) -> usize {
+    items.iter().filter(|i| is_work(i)).count()
+}
+
+impl Circuit {
+    pub fn is_empty(&self) -> bool {
+        self.stations.is_empty()
+    }
+    /// The group at `station` in `round`, if any: there may be fewer groups
+    /// than stations.
+    pub fn group_at(&self, station: usize, round: usize) -> Option<&str> {
+        let n = self.stations.len();
+        if station >= n {
+            return None;
+        }
+        let g = (station + n - round % n) % n;
+        self.groups.get(g).map(String::as_str)
+    }
+    /// Who's where: a row for each round, with the group at each station.
+    pub fn matrix(&self, rounds: usize) -> Vec<Vec<Option<&str>>> {
+        (0..rounds)
+            .map(|r| {
+                (0..self.stations.len())
+                    .map(|s| self.group_at(s, r))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn rotates() {
+        let c = Circuit {
+            stations: parse_list("Squats, Press ups,, Burpees "),
+            groups: parse_list("A,B"),
+        };
+        assert_eq!(c.stations, vec!["Squats", "Press ups", "Burpees"]);
+        assert_eq!(
+            c.matrix(4),
+            vec![
+                vec![Some("A"), Some("B"), None],
+                vec![None, Some("A"), Some("B")],
+                vec![Some("B"), None, Some("A")],
+                vec![Some("A"), Some("B"), None],
+            ]
+        );
+        assert_eq!(c.group_at(3, 0), None);
+    }
+    #[test]
+    pub fn rounds_move_after_rest() {
+        let warm_up = FlatStatus {
+            kind: Some(Kind::WarmUp),
+            ..FlatStatus::new("Jog", 60)
+        };
+        let items: Vec<_> = Some(warm_up)
+            .into_iter()
+            .chain(
+                ["Work", "Rest", "Work", "Rest", "Work"]
+                    .iter()
+                    .map(|n| FlatStatus::new(n, 30)),
+            )
+            .collect();
+        let at: Vec<_> = (0..items.len()).map(|ix| round(&items, ix)).collect();
+        assert_eq!(at, vec![0, 0, 0, 1, 1, 2]);
+        assert_eq!(rounds(&items), 3);
+    }
+}
@@ -0,0 +1,1171 @@
+//! The workout timer itself, free of seed and the browser. Time comes in
+//! through a `Clock`, and anything that should happen in the outside world
+//! (beeps, telling the room) comes back out as a list of `Effect`s.
+use crate::workout::{CueAt, FlatStatus, Kind};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub trait Clock {
+    /// Milliseconds since the epoch.
+    fn now(&self) -> i64;
+}
+
+/// Times are for the session as a whole, not the current item: every item
+/// boundary is the session start plus the planned durations before it. That
+/// way every device in the room agrees on exactly when each item starts, and
+/// slow ticks don't add up.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RunningState {
+    /// When the session would have started had it never been paused or
+    /// jumped around in.
+    RunningSince(i64),
+    /// Millis into the session.
+    PausedAfter(i64),
+    /// Ran off the end of the routine at this time.
+    Finished(i64),
+    Config,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LogEvent {
+    Started,
+    Finished,
+    /// The item's planned time was changed by this much.
+    Adjusted {
+        ix: usize,
+        millis: i64,
+    },
+    Paused,
+    Resumed,
+    Jumped {
+        from: usize,
+        to: usize,
+    },
+    Undone,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    pub at: i64,
+    pub event: LogEvent,
+}
+
+/// How things stood just before someone paused, resumed or jumped, so that
+/// it can be undone.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub at: i64,
+    pub state: RunningState,
+    pub routine_ix: usize,
+}
+
+const HISTORY_LEN: usize = 20;
+/// Entries kept in the log, which goes out with every announcement. The
+/// first, when the session started, is always kept.
+const LOG_LEN: usize = 100;
+/// Seconds at the end of a scheduled start's countdown which get a beep.
+const COUNTDOWN_CUES: i64 = 5;
+
+/// The part of the engine state which is shared with the room.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Session {
+    pub state: RunningState,
+    pub routine_ix: usize,
+    /// Millis added to (or taken from) the planned time of each item.
+    #[serde(default)]
+    pub adjustments: BTreeMap<usize, i64>,
+    /// Only the latest `LOG_LEN` entries, so a summary of a very long
+    /// session may miss early pauses and jumps.
+    #[serde(default)]
+    pub log: Vec<LogEntry>,
+    /// Shared, so that a stray tap on one device can be undone from another.
+    #[serde(default)]
+    pub history: Vec<Snapshot>,
+}
+
+/// How a finished session went.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    /// Millis the routine should take, as written.
+    pub planned: i64,
+    /// Millis from start to finish, pauses and all.
+    pub actual: i64,
+    pub pauses: usize,
+    /// Items cut short or passed over by jumping forward.
+    pub skipped: usize,
+    pub adjusted: usize,
+}
+
+/// Something worth making a noise about. What it sounds like is up to the
+/// caller.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cue {
+    /// Started, paused or resumed.
+    Go,
+    /// Moved on to the item with this index.
+    Enter(usize),
+    /// This many seconds left of the current item.
+    Warning(i64),
+    /// This many seconds until a scheduled start.
+    Countdown(i64),
+    /// The item at this index has a cue of its own here: the cue's index.
+    Item(usize, usize),
+    /// Half way through the work item at this index.
+    Halfway(usize),
+    /// A beat of the cadence of the item at this index: the beat's number.
+    /// Only ever lined up ahead, never given by `tick`.
+    Beat(usize, usize),
+}
+
+/// How far along things are.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    /// Fraction of the current item gone, if it has an end.
+    pub item: Option<f64>,
+    /// Millis of the session gone.
+    pub done: i64,
+    /// Millis the session is planned to take, adjustments included.
+    pub total: i64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Effect {
+    /// Moved on to the item with this index.
+    Transition(usize),
+    Cue(Cue),
+    /// The session has changed and the room should be told.
+    Announce,
+}
+
+lazy_static! {
+    static ref END_STATUS: FlatStatus = FlatStatus {
+        duration: None,
+        kind: Some(Kind::Rest),
+        ..FlatStatus::new("END", 0)
+    };
+}
+
+pub struct Engine {
+    pub session: Session,
+    /// Millis before the end of an item to start counting down.
+    pub warning: i64,
+    /// Whether to cue half way through work items.
+    pub halfway: bool,
+    last_update: i64,
+    /// Item cues already played: (item, cue, repeat). Only ever for the
+    /// current item. Half way is cue `usize::MAX`.
+    fired: BTreeSet<(usize, usize, usize)>,
+}
+
+impl Session {
+    pub fn init() -> Self {
+        Self {
+            state: RunningState::Config,
+            routine_ix: 0,
+            adjustments: BTreeMap::new(),
+            log: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+    fn log(&mut self, at: i64, event: LogEvent) {
+        self.log.push(LogEntry { at, event });
+        if self.log.len() > LOG_LEN {
+            self.log.remove(1);
+        }
+    }
+    fn remember(&mut self, at: i64) {
+        if let RunningState::Config | RunningState::Finished(_) = self.state {
+            return;
+        }
+        self.history.push(Snapshot {
+            at,
+            state: self.state.clone(),
+            routine_ix: self.routine_ix,
+        });
+        if self.history.len() > HISTORY_LEN {
+            self.history.remove(0);
+        }
+    }
+    /// Planned length of item `ix` in millis, adjustments included. `None`
+    /// for items with no end, like END.
+    pub fn item_duration(&self, items: &[FlatStatus], ix: usize) -> Option<i64> {
+        let planned = items.get(ix)?.duration? as i64 * 1000;
+        let adjustment = self.adjustments.get(&ix).copied().unwrap_or(0);
+        Some((planned + adjustment).max(0))
+    }
+    /// Planned start of item `ix`, in millis from the start of the session.
+    pub fn item_start(&self, items: &[FlatStatus], ix: usize) -> i64 {
+        (0..ix.min(items.len()))
+            .map(|i| self.item_duration(items, i).unwrap_or(0))
+            .sum()
+    }
+    /// When this session was (or is due to be) started.
+    pub fn started(&self) -> Option<i64> {
+        match self.state {
+            RunningState::Config => None,
+            _ => self
+                .log
+                .iter()
+                .find(|e| e.event == LogEvent::Started)
+                .map(|e| e.at),
+        }
+    }
+    pub fn summary(&self, items: &[FlatStatus]) -> Option<Summary> {
+        match self.state {
+            RunningState::Finished(at) => Some(self.summary_at(items, at)),
+            _ => None,
+        }
+    }
+    /// How the session went, had it ended at `end`.
+    pub fn summary_at(&self, items: &[FlatStatus], end: i64) -> Summary {
+        let started = self
+            .log
+            .iter()
+            .find(|e| e.event == LogEvent::Started)
+            .map_or(end, |e| e.at);
+        Summary {
+            planned: items
+                .iter()
+                .map(|x| x.duration.unwrap_or(0) as i64 * 1000)
+                .sum(),
+            actual: end - started,
+            pauses: self
+                .log
+                .iter()
+                .filter(|e| e.event == LogEvent::Paused)
+                .count(),
+            skipped: self
+                .log
+                .iter()
+                .map(|e| match e.event {
+                    LogEvent::Jumped { from, to } if to > from => to - from,
+                    _ => 0,
+                })
+                .sum(),
+            adjusted: self.adjustments.values().filter(|&&a| a != 0).count(),
+        }
+    }
+    /// The item that should be on `elapsed` millis into the session. Past
+    /// the end, that's the END item at `items.len()`.
+    fn item_at(&self, items: &[FlatStatus], elapsed: i64) -> usize {
+        let mut end = 0;
+        for ix in 0..items.len() {
+            match self.item_duration(items, ix) {
+                Some(d) => end += d,
+                None => return ix,
+            }
+            if end > elapsed {
+                return ix;
+            }
+        }
+        items.len()
+    }
+}
+
+impl Engine {
+    pub fn new(clock: &impl Clock) -> Self {
+        Self {
+            session: Session::init(),
+            warning: 3000,
+            halfway: false,
+            last_update: clock.now(),
+            fired: BTreeSet::new(),
+        }
+    }
+    /// Millis into the session as a whole.
+    pub fn session_elapsed(&self) -> i64 {
+        match self.session.state {
+            RunningState::RunningSince(start) if self.last_update > start => {
+                self.last_update - start
+            }
+            RunningState::RunningSince(_) => 0, //Start is in the future. Sad times.
+            RunningState::PausedAfter(p) => p,
+            RunningState::Finished(_) | RunningState::Config => 0,
+        }
+    }
+    /// Millis until a scheduled start, if we're waiting for one.
+    pub fn countdown_millis(&self) -> Option<i64> {
+        match self.session.state {
+            RunningState::RunningSince(start) if start > self.last_update => {
+                Some(start - self.last_update)
+            }
+            _ => None,
+        }
+    }
+    /// Millis into the current item.
+    pub fn elapsed_millis(&self, items: &[FlatStatus]) -> i64 {
+        self.session_elapsed() - self.session.item_start(items, self.session.routine_ix)
+    }
+    /// Millis left of the current item, if it has an end.
+    pub fn remaining_millis(&self, items: &[FlatStatus]) -> Option<i64> {
+        self.session
+            .item_duration(items, self.session.routine_ix)
+            .map(|d| d - self.elapsed_millis(items))
+    }
+    pub fn progress(&self, items: &[FlatStatus]) -> Progress {
+        let total = self.session.item_start(items, items.len());
+        let item = self
+            .session
+            .item_duration(items, self.session.routine_ix)
+            .map(|d| match d {
+                0 => 1.,
+                d => (self.elapsed_millis(items) as f64 / d as f64)
+                    .max(0.)
+                    .min(1.),
+            });
+        Progress {
+            item,
+            done: self.session_elapsed().max(0).min(total),
+            total,
+        }
+    }
+    pub fn get_item<'a>(&self, items: &'a [FlatStatus], ix: usize) -> &'a FlatStatus {
+        items.get(ix).unwrap_or(&END_STATUS)
+    }
+    pub fn current_item<'a>(&self, items: &'a [FlatStatus]) -> Option<&'a FlatStatus> {
+        match self.session.state {
+            RunningState::Config => None,
+            _ => Some(self.get_item(items, self.session.routine_ix)),
+        }
+    }
+    /// Start from the top, or pause or resume.
+    pub fn go(&mut self, clock: &impl Clock) -> Vec<Effect> {
+        let now = clock.now();
+        self.session.remember(now);
+        self.session.state = match self.session.state {
+            // Waiting for a scheduled start: start now instead.
+            RunningState::RunningSince(start) if start > now => {
+                for e in &mut self.session.log {
+                    if e.event == LogEvent::Started {
+                        e.at = now;
+                    }
+                }
+                RunningState::RunningSince(now)
+            }
+            RunningState::RunningSince(start) => {
+                self.session.log(now, LogEvent::Paused);
+                RunningState::PausedAfter(now - start)
+            }
+            RunningState::PausedAfter(done) => {
+                self.session.log(now, LogEvent::Resumed);
+                RunningState::RunningSince(now - done)
+            }
+            RunningState::Config | RunningState::Finished(_) => {
+                self.session = Session::init();
+                self.session.log(now, LogEvent::Started);
+                RunningState::RunningSince(now)
+            }
+        };
+        vec![Effect::Cue(Cue::Go), Effect::Announce]
+    }
+    /// Start from the top at a set time, so that everyone in the room can
+    /// count down to it together.
+    pub fn start_at(&mut self, at: i64) -> Vec<Effect> {
+        self.session = Session::init();
+        self.session.state = RunningState::RunningSince(at);
+        self.session.log(at, LogEvent::Started);
+        vec![Effect::Announce]
+    }
+    pub fn to_config(&mut self) -> Vec<Effect> {
+        self.session.state = RunningState::Config;
+        vec![Effect::Announce]
+    }
+    pub fn goto_item(
+        &mut self,
+        items: &[FlatStatus],
+        new_ix: usize,
+        clock: &impl Clock,
+    ) -> Vec<Effect> {
+        let mut effects = vec![Effect::Transition(new_ix)];
+        let now = clock.now();
+        if let RunningState::RunningSince(_) | RunningState::PausedAfter(_) = self.session.state {
+            self.session.remember(now);
+            let from = self.session.routine_ix;
+            self.session.log(now, LogEvent::Jumped { from, to: new_ix });
+        }
+        self.session.routine_ix = new_ix;
+        let offset = self.session.item_start(items, new_ix);
+        match self.session.state {
+            RunningState::RunningSince(_) => {
+                self.session.state = RunningState::RunningSince(now - offset);
+                effects.push(Effect::Cue(Cue::Enter(new_ix)));
+            }
+            RunningState::PausedAfter(_) => {
+                self.session.state = RunningState::PausedAfter(offset);
+            }
+            RunningState::Finished(_) | RunningState::Config => {}
+        }
+        self.fired.clear();
+        effects.push(Effect::Announce);
+        effects
+    }
+    /// Catch up with the clock: move on when the current item runs out, and
+    /// count down the last few seconds.
+    ///
+    /// If several items ran out since the last tick (a throttled tab, say), we
+    /// land straight on the right one, part way through, and only cue that.
+    pub fn tick(&mut self, items: &[FlatStatus], clock: &impl Clock) -> Vec<Effect> {
+        let old_elapsed = self.session_elapsed();
+        let old_now = self.last_update;
+        self.last_update = clock.now();
+        let elapsed = self.session_elapsed();
+        if let RunningState::RunningSince(start) = self.session.state {
+            if start > old_now {
+                let left = start - self.last_update;
+                if left > 0 {
+                    let secs = (left + 999) / 1000;
+                    if secs <= COUNTDOWN_CUES && secs != (start - old_now + 999) / 1000 {
+                        return vec![Effect::Cue(Cue::Countdown(secs))];
+                    }
+                    return Vec::new();
+                }
+                let ix = self.session.routine_ix;
+                if self.session.item_at(items, elapsed) == ix {
+                    return vec![Effect::Transition(ix), Effect::Cue(Cue::Enter(ix))];
+                }
+            }
+            let ix = self.session.item_at(items, elapsed);
+            if ix >= items.len() {
+                // Stop the clock at the exact planned end.
+                let at = start + self.session.item_start(items, ix);
+                self.session.routine_ix = ix;
+                self.session.state = RunningState::Finished(at);
+                self.session.log(at, LogEvent::Finished);
+                return vec![
+                    Effect::Transition(ix),
+                    Effect::Cue(Cue::Enter(ix)),
+                    Effect::Announce,
+                ];
+            }
+            if ix != self.session.routine_ix {
+                self.session.routine_ix = ix;
+                return vec![
+                    Effect::Transition(ix),
+                    Effect::Cue(Cue::Enter(ix)),
+                    Effect::Announce,
+                ];
+            }
+        }
+        let ix = self.session.routine_ix;
+        let mut effects = self.item_cues(items, old_elapsed, elapsed);
+        if let (Some(d), Some(_)) = (
+            self.session.item_duration(items, ix),
+            self.current_item(items),
+        ) {
+            let end = self.session.item_start(items, ix) + d;
+            let remaining_millis = end - elapsed;
+            if remaining_millis < self.warning {
+                let whole_rem_now = remaining_millis / 1000;
+                let whole_rem_before = (end - old_elapsed) / 1000;
+                if whole_rem_before != whole_rem_now {
+                    effects.push(Effect::Cue(Cue::Warning(whole_rem_now + 1)));
+                }
+            }
+        }
+        effects
+    }
+    /// The current item's own cues that came up between two ticks, each
+    /// played only once per pass. Jumping, adjusting or undoing starts a new
+    /// pass, so that cues moved ahead of us go off again when reached. Like
+    /// the beeps of skipped items, cues passed over while catching up aren't
+    /// played.
+    fn item_cues(&mut self, items: &[FlatStatus], old_elapsed: i64, elapsed: i64) -> Vec<Effect> {
+        let ix = self.session.routine_ix;
+        if self.fired.iter().next().map_or(false, |f| f.0 != ix) {
+            self.fired.clear();
+        }
+        let (item, duration) = match (items.get(ix), self.session.item_duration(items, ix)) {
+            (Some(i), Some(d)) => (i, d),
+            _ => return Vec::new(),
+        };
+        let start = self.session.item_start(items, ix);
+        let (from, to) = (old_elapsed - start, elapsed - start);
+        let mut effects = Vec::new();
+        if self.halfway && !item.is_rest() {
+            let due = CueAt::Fraction(0.5)
+                .offsets(duration)
+                .into_iter()
+                .any(|(_, o)| o > from && o <= to);
+            if due && self.fired.insert((ix, usize::MAX, 0)) {
+                effects.push(Effect::Cue(Cue::Halfway(ix)));
+            }
+        }
+        for (c, cue) in item.cues.iter().enumerate() {
+            let due = cue
+                .at
+                .offsets(duration)
+                .into_iter()
+                .filter(|&(_, o)| o > from && o <= to)
+                .last();
+            if let Some((n, _)) = due {
+                if self.fired.insert((ix, c, n)) {
+                    effects.push(Effect::Cue(Cue::Item(ix, c)));
+                }
+            }
+        }
+        effects
+    }
+    /// Give the current item more (or less) time. It can't be cut short of
+    /// what has already gone by.
+    pub fn adjust(&mut self, items: &[FlatStatus], millis: i64, clock: &impl Clock) -> Vec<Effect> {
+        self.last_update = clock.now();
+        let ix = self.session.routine_ix;
+        let duration = match (
+            self.current_item(items),
+            self.session.item_duration(items, ix),
+        ) {
+            (Some(_), Some(d)) => d,
+            _ => return Vec::new(),
+        };
+        let change = (duration + millis).max(self.elapsed_millis(items)) - duration;
+        if change == 0 {
+            return Vec::new();
+        }
+        *self.session.adjustments.entry(ix).or_insert(0) += change;
+        self.fired.clear();
+        self.session
+            .log(self.last_update, LogEvent::Adjusted { ix, millis: change });
+        vec![Effect::Announce]
+    }
+    /// Go back to how things were before the last pause, resume or jump,
+    /// with the same time gone on that item as there was then.
+    pub fn undo(&mut self, clock: &impl Clock) -> Vec<Effect> {
+        let snapshot = match self.session.history.pop() {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        let now = clock.now();
+        self.session.state = match snapshot.state {
+            RunningState::RunningSince(start) => {
+                RunningState::RunningSince(start + now - snapshot.at)
+            }
+            other => other,
+        };
+        let mut effects = Vec::new();
+        if snapshot.routine_ix != self.session.routine_ix {
+            effects.push(Effect::Transition(snapshot.routine_ix));
+        }
+        self.session.routine_ix = snapshot.routine_ix;
+        self.session.log(now, LogEvent::Undone);
+        self.fired.clear();
+        effects.push(Effect::Announce);
+        effects
+    }
+    /// Take on a session announced by someone else in the room. If that
+    /// moves us to another item, it's cued just as if we'd moved ourselves.
+    pub fn apply_remote(&mut self, session: Session) -> Vec<Effect> {
+        let moved = session.routine_ix != self.session.routine_ix;
+        let audible = match session.state {
+            RunningState::RunningSince(start) => start <= self.last_update,
+            RunningState::Finished(_) => true,
+            RunningState::PausedAfter(_) | RunningState::Config => false,
+        };
+        self.session = session;
+        let ix = self.session.routine_ix;
+        match (moved, audible) {
+            (false, _) => Vec::new(),
+            (true, false) => vec![Effect::Transition(ix)],
+            (true, true) => vec![Effect::Transition(ix), Effect::Cue(Cue::Enter(ix))],
+        }
+    }
+    /// The cues `tick` will give in the next `horizon` millis if nothing
+    /// changes, with when they're due, so that they can be lined up ahead.
+    /// Beats are only here, as they're too many and too exact to be left to
+    /// ticks.
+    pub fn upcoming(&self, items: &[FlatStatus], horizon: i64) -> Vec<(i64, Cue)> {
+        let start = match self.session.state {
+            RunningState::RunningSince(start) => start,
+            _ => return Vec::new(),
+        };
+        let (now, until) = (self.last_update, self.last_update + horizon);
+        let current = self.session.routine_ix;
+        let mut ans = Vec::new();
+        if start > now {
+            ans.extend((1..=COUNTDOWN_CUES).map(|k| (start - k * 1000, Cue::Countdown(k))));
+            ans.push((start, Cue::Enter(current)));
+        }
+        for ix in current..=items.len() {
+            let item_start = start + self.session.item_start(items, ix);
+            if item_start > until {
+                break;
+            }
+            if ix > current {
+                ans.push((item_start, Cue::Enter(ix)));
+            }
+            let (item, d) = match (items.get(ix), self.session.item_duration(items, ix)) {
+                (Some(i), Some(d)) => (i, d),
+                _ => break,
+            };
+            let unfired = |c, n| ix != current || !self.fired.contains(&(ix, c, n));
+            if self.halfway && !item.is_rest() && unfired(usize::MAX, 0) {
+                for (_, o) in CueAt::Fraction(0.5).offsets(d) {
+                    ans.push((item_start + o, Cue::Halfway(ix)));
+                }
+            }
+            for (c, cue) in item.cues.iter().enumerate() {
+                for (n, o) in cue.at.offsets(d) {
+                    if unfired(c, n) {
+                        ans.push((item_start + o, Cue::Item(ix, c)));
+                    }
+                }
+            }
+            if let Some(cadence) = item.cadence {
+                let beats = cadence.beats(d).into_iter();
+                ans.extend(beats.map(|(n, o)| (item_start + o, Cue::Beat(ix, n))));
+            }
+            let end = item_start + d;
+            ans.extend(
+                (1..)
+                    .map(|k| k * 1000)
+                    .take_while(|&w| w <= self.warning && w <= d)
+                    .map(|w| (end - w, Cue::Warning(w / 1000))),
+            );
+        }
+        ans.retain(|&(at, _)| at > now && at <= until);
+        ans.sort_by_key(|&(at, _)| at);
+        ans
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    pub struct VirtualClock(Cell<i64>);
+    impl Clock for VirtualClock {
+        fn now(&self) -> i64 {
+            self.0.get()
+        }
+    }
+    impl VirtualClock {
+        pub fn advance(&self, millis: i64) {
+            self.0.set(self.0.get() + millis);
+        }
+    }
+
+    fn item(name: &str, duration: u32) -> FlatStatus {
+        FlatStatus::new(name, duration)
+    }
+    fn warn(secs: i64) -> Effect {
+        Effect::Cue(Cue::Warning(secs))
+    }
+    fn enter(ix: usize) -> Effect {
+        Effect::Cue(Cue::Enter(ix))
+    }
+
+    /// Runs the engine, ticking every `step` millis, returning the effects
+    /// along with the virtual time they happened at.
+    fn run(
+        engine: &mut Engine,
+        items: &[FlatStatus],
+        clock: &VirtualClock,
+        step: i64,
+        until: i64,
+    ) -> Vec<(i64, Effect)> {
+        let mut ans = Vec::new();
+        while clock.now() < until {
+            clock.advance(step);
+            for e in engine.tick(items, clock) {
+                ans.push((clock.now(), e));
+            }
+        }
+        ans
+    }
+
+    #[test]
+    pub fn whole_routine() {
+        let items = vec![item("Work", 2), item("rest", 1), item("Work", 2)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        assert_eq!(
+            engine.go(&clock),
+            vec![Effect::Cue(Cue::Go), Effect::Announce]
+        );
+        let effects = run(&mut engine, &items, &clock, 100, 6000);
+        assert_eq!(
+            effects,
+            vec![
+                (100, warn(2)),
+                (1100, warn(1)),
+                (2000, Effect::Transition(1)),
+                (2000, enter(1)),
+                (2000, Effect::Announce),
+                (2100, warn(1)),
+                (3000, Effect::Transition(2)),
+                (3000, enter(2)),
+                (3000, Effect::Announce),
+                (3100, warn(2)),
+                (4100, warn(1)),
+                (5000, Effect::Transition(3)),
+                (5000, enter(3)),
+                (5000, Effect::Announce),
+            ]
+        );
+        assert_eq!(engine.current_item(&items).unwrap().name, "END");
+    }
+
+    #[test]
+    pub fn pause_and_resume() {
+        let items = vec![item("Work", 10)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        clock.advance(4000);
+        engine.go(&clock);
+        assert_eq!(engine.session.state, RunningState::PausedAfter(4000));
+        clock.advance(60_000);
+        assert!(run(&mut engine, &items, &clock, 1000, 70_000).is_empty());
+        engine.go(&clock);
+        clock.advance(1000);
+        engine.tick(&items, &clock);
+        assert_eq!(engine.elapsed_millis(&items), 5000);
+    }
+
+    #[test]
+    pub fn catches_up_after_a_long_gap() {
+        let items = vec![
+            item("Work", 30),
+            item("rest", 10),
+            item("Work", 30),
+            item("rest", 10),
+            item("Work", 30),
+        ];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        engine.tick(&items, &clock);
+        // Tab is throttled for 65 seconds: two items go by entirely.
+        clock.advance(65_000);
+        assert_eq!(
+            engine.tick(&items, &clock),
+            vec![Effect::Transition(2), enter(2), Effect::Announce]
+        );
+        assert_eq!(engine.session.state, RunningState::RunningSince(0));
+        assert_eq!(engine.elapsed_millis(&items), 25_000);
+        // No stray countdown beeps on the next tick either.
+        clock.advance(100);
+        assert!(engine.tick(&items, &clock).is_empty());
+    }
+
+    #[test]
+    pub fn boundaries_do_not_drift() {
+        let items: Vec<_> = (0..60)
+            .map(|i| item(if i % 2 == 0 { "Work" } else { "rest" }, 7))
+            .collect();
+        let end = Session::init().item_start(&items, items.len());
+        let clock = VirtualClock(Cell::new(0));
+        let mut a = Engine::new(&clock);
+        a.go(&clock);
+        let mut b = Engine::new(&clock);
+        b.apply_remote(a.session.clone());
+        // Two devices with different, awkward frame rates.
+        let at_a: Vec<_> = run(&mut a, &items, &clock, 170, end + 1000)
+            .into_iter()
+            .filter(|(_, e)| *e == Effect::Transition(items.len()))
+            .collect();
+        clock.0.set(0);
+        let at_b: Vec<_> = run(&mut b, &items, &clock, 330, end + 1000)
+            .into_iter()
+            .filter(|(_, e)| *e == Effect::Transition(items.len()))
+            .collect();
+        assert_eq!(at_a, vec![(420_070, Effect::Transition(60))]);
+        assert_eq!(at_b, vec![(420_090, Effect::Transition(60))]);
+        assert_eq!(a.session, b.session);
+        assert_eq!(a.session.state, RunningState::Finished(420_000));
+    }
+
+    #[test]
+    pub fn jump_keeps_schedule_anchored() {
+        let items = vec![item("Work", 30), item("rest", 10), item("Work", 30)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        clock.advance(5000);
+        engine.goto_item(&items, 2, &clock);
+        assert_eq!(engine.session.state, RunningState::RunningSince(-35_000));
+        engine.tick(&items, &clock);
+        assert_eq!(engine.elapsed_millis(&items), 0);
+        engine.go(&clock);
+        engine.goto_item(&items, 1, &clock);
+        assert_eq!(engine.session.state, RunningState::PausedAfter(30_000));
+    }
+
+    #[test]
+    pub fn adjustments() {
+        let items = vec![item("Work", 30), item("rest", 10), item("Work", 30)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        clock.advance(25_000);
+        assert_eq!(
+            engine.adjust(&items, 10_000, &clock),
+            vec![Effect::Announce]
+        );
+        assert_eq!(engine.remaining_millis(&items), Some(15_000));
+        clock.advance(10_000);
+        assert!(engine.tick(&items, &clock).is_empty());
+        // Can't take off more than is left.
+        engine.adjust(&items, -10_000, &clock);
+        assert_eq!(engine.remaining_millis(&items), Some(0));
+        assert_eq!(
+            engine.tick(&items, &clock),
+            vec![Effect::Transition(1), enter(1), Effect::Announce]
+        );
+        assert_eq!(engine.session.item_start(&items, 2), 45_000);
+        assert_eq!(
+            engine.session.log[1..],
+            [
+                LogEntry {
+                    at: 25_000,
+                    event: LogEvent::Adjusted {
+                        ix: 0,
+                        millis: 10_000
+                    }
+                },
+                LogEntry {
+                    at: 35_000,
+                    event: LogEvent::Adjusted {
+                        ix: 0,
+                        millis: -5_000
+                    }
+                },
+            ]
+        );
+    }
+
+    #[test]
+    pub fn log_is_capped() {
+        let items = vec![item("Work", 30)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        for _ in 0..LOG_LEN {
+            clock.advance(1000);
+            engine.go(&clock);
+        }
+        assert_eq!(engine.session.log.len(), LOG_LEN);
+        assert_eq!(engine.session.started(), Some(0));
+        // The first pause has gone to make room.
+        let summary = engine.session.summary_at(&items, 200_000);
+        assert_eq!(summary.pauses, LOG_LEN / 2 - 1);
+    }
+
+    #[test]
+    pub fn undo_restores_item_and_time() {
+        let items = vec![item("Work", 30), item("rest", 10), item("Work", 30)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        clock.advance(12_000);
+        engine.goto_item(&items, 2, &clock);
+        clock.advance(3_000);
+        assert_eq!(
+            engine.undo(&clock),
+            vec![Effect::Transition(0), Effect::Announce]
+        );
+        engine.tick(&items, &clock);
+        assert_eq!(engine.session.routine_ix, 0);
+        assert_eq!(engine.elapsed_millis(&items), 12_000);
+        // Undo a pause: carry on from where it was paused.
+        engine.go(&clock);
+        clock.advance(60_000);
+        engine.undo(&clock);
+        engine.tick(&items, &clock);
+        assert_eq!(engine.elapsed_millis(&items), 12_000);
+        assert_eq!(
+            engine.session.state,
+            RunningState::RunningSince(clock.now() - 12_000)
+        );
+        assert!(engine.undo(&clock).is_empty());
+    }
+
+    #[test]
+    pub fn scheduled_start() {
+        let items = vec![item("Work", 30)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        assert_eq!(engine.start_at(10_000), vec![Effect::Announce]);
+        engine.tick(&items, &clock);
+        assert_eq!(engine.countdown_millis(), Some(10_000));
+        let effects = run(&mut engine, &items, &clock, 100, 11_000);
+        assert_eq!(
+            effects,
+            vec![
+                (5000, Effect::Cue(Cue::Countdown(5))),
+                (6000, Effect::Cue(Cue::Countdown(4))),
+                (7000, Effect::Cue(Cue::Countdown(3))),
+                (8000, Effect::Cue(Cue::Countdown(2))),
+                (9000, Effect::Cue(Cue::Countdown(1))),
+                (10_000, Effect::Transition(0)),
+                (10_000, enter(0)),
+            ]
+        );
+        assert_eq!(engine.countdown_millis(), None);
+        assert_eq!(engine.elapsed_millis(&items), 1000);
+    }
+    #[test]
+    pub fn go_cuts_countdown_short() {
+        let items = vec![item("Work", 30)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.start_at(10_000);
+        clock.advance(2000);
+        engine.go(&clock);
+        assert_eq!(engine.session.state, RunningState::RunningSince(2000));
+        assert_eq!(engine.session.started(), Some(2000));
+        assert_eq!(engine.session.summary_at(&items, 32_000).actual, 30_000);
+    }
+
+    #[test]
+    pub fn finishes_with_summary() {
+        let items = vec![item("Work", 30), item("rest", 10), item("Work", 30)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        clock.advance(5_000);
+        engine.go(&clock);
+        clock.advance(20_000);
+        engine.go(&clock);
+        engine.goto_item(&items, 2, &clock);
+        engine.adjust(&items, 10_000, &clock);
+        assert_eq!(engine.session.summary(&items), None);
+        let effects: Vec<_> = run(&mut engine, &items, &clock, 500, 100_000)
+            .into_iter()
+            .filter(|(_, e)| !matches!(e, Effect::Cue(Cue::Warning(_))))
+            .collect();
+        assert_eq!(
+            effects,
+            vec![
+                (65_000, Effect::Transition(3)),
+                (65_000, enter(3)),
+                (65_000, Effect::Announce)
+            ]
+        );
+        assert_eq!(engine.session.state, RunningState::Finished(65_000));
+        assert_eq!(
+            engine.session.summary(&items),
+            Some(Summary {
+                planned: 70_000,
+                actual: 65_000,
+                pauses: 1,
+                skipped: 2,
+                adjusted: 1
+            })
+        );
+    }
+
+    #[test]
+    pub fn longer_warning() {
+        let items = vec![item("Work", 10)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.warning = 5000;
+        engine.go(&clock);
+        let effects: Vec<_> = run(&mut engine, &items, &clock, 250, 9750)
+            .into_iter()
+            .map(|(_, e)| e)
+            .collect();
+        assert_eq!(effects, (1..=5).rev().map(warn).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn item_cues_fire_once() {
+        use crate::workout::{CueAt, ItemCue};
+        let mut stretch = item("Stretch", 60);
+        stretch.cues = vec![
+            ItemCue::tick(CueAt::Fraction(0.5)),
+            ItemCue::tick(CueAt::Every(20)),
+        ];
+        let items = vec![stretch, item("rest", 10)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        let item_cues = |effects: Vec<(i64, Effect)>| {
+            effects
+                .into_iter()
+                .filter(|(_, e)| matches!(e, Effect::Cue(Cue::Item(..))))
+                .collect::<Vec<_>>()
+        };
+        // Paused across the halfway mark for a while: still only once.
+        let effects = item_cues(run(&mut engine, &items, &clock, 100, 29_900));
+        assert_eq!(effects, vec![(20_000, Effect::Cue(Cue::Item(0, 1)))]);
+        engine.go(&clock);
+        run(&mut engine, &items, &clock, 100, 40_000);
+        engine.go(&clock);
+        let effects = item_cues(run(&mut engine, &items, &clock, 100, 51_000));
+        assert_eq!(
+            effects,
+            vec![
+                (40_100, Effect::Cue(Cue::Item(0, 0))),
+                (50_100, Effect::Cue(Cue::Item(0, 1)))
+            ]
+        );
+        // Stretching the item moves halfway back ahead of us, so it goes off
+        // again when we get there.
+        engine.adjust(&items, 40_000, &clock);
+        assert_eq!(
+            item_cues(run(&mut engine, &items, &clock, 100, 62_000)),
+            vec![(60_100, Effect::Cue(Cue::Item(0, 0)))]
+        );
+    }
+
+    #[test]
+    pub fn restarting_an_item_replays_its_cues() {
+        use crate::workout::{CueAt, ItemCue};
+        let mut work = item("Work", 30);
+        work.cues = vec![ItemCue::tick(CueAt::Seconds(10))];
+        let items = vec![work, item("rest", 10)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.halfway = true;
+        engine.go(&clock);
+        let cues = |effects: Vec<(i64, Effect)>| {
+            effects
+                .into_iter()
+                .filter(|(_, e)| {
+                    matches!(e, Effect::Cue(Cue::Item(..)) | Effect::Cue(Cue::Halfway(_)))
+                })
+                .map(|(_, e)| e)
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![Effect::Cue(Cue::Item(0, 0)), Effect::Cue(Cue::Halfway(0))];
+        assert_eq!(
+            cues(run(&mut engine, &items, &clock, 100, 20_000)),
+            expected
+        );
+        engine.goto_item(&items, 0, &clock);
+        assert_eq!(
+            cues(run(&mut engine, &items, &clock, 100, 40_000)),
+            expected
+        );
+    }
+
+    #[test]
+    pub fn remote_jumps_are_cued() {
+        let items = vec![item("Work", 30), item("rest", 10), item("Work", 30)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut a = Engine::new(&clock);
+        let mut b = Engine::new(&clock);
+        a.go(&clock);
+        assert!(b.apply_remote(a.session.clone()).is_empty());
+        clock.advance(1000);
+        a.goto_item(&items, 2, &clock);
+        b.tick(&items, &clock);
+        assert_eq!(
+            b.apply_remote(a.session.clone()),
+            vec![Effect::Transition(2), enter(2)]
+        );
+        // Moved on by the clock at the same time on both: only cued once.
+        clock.advance(30_000);
+        assert_eq!(
+            b.tick(&items, &clock),
+            vec![Effect::Transition(3), enter(3), Effect::Announce]
+        );
+        a.tick(&items, &clock);
+        assert!(b.apply_remote(a.session.clone()).is_empty());
+    }
+
+    #[test]
+    pub fn upcoming_matches_ticks() {
+        use crate::workout::{CueAt, ItemCue};
+        let mut work = item("Work", 4);
+        work.cues = vec![ItemCue::tick(CueAt::Seconds(1))];
+        let items = vec![work, item("rest", 2), item("Work", 3)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.halfway = true;
+        engine.start_at(3000);
+        engine.tick(&items, &clock);
+        let plan = engine.upcoming(&items, 60_000);
+        // Ticks notice a cue on the first tick after it's due.
+        let cues: Vec<_> = run(&mut engine, &items, &clock, 1, 13_000)
+            .into_iter()
+            .filter_map(|(t, e)| match e {
+                Effect::Cue(c) => Some((t, c)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(plan.len(), cues.len());
+        for (&(at, planned), &(t, cue)) in plan.iter().zip(cues.iter()) {
+            assert_eq!(planned, cue);
+            assert!(t - at <= 1, "{:?} due at {} but played at {}", cue, at, t);
+        }
+        assert!(engine.upcoming(&items, 60_000).is_empty());
+    }
+
+    #[test]
+    pub fn upcoming_follows_changes() {
+        let items = vec![item("Work", 30), item("rest", 10)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        engine.tick(&items, &clock);
+        assert_eq!(
+            engine.upcoming(&items, 30_000),
+            vec![
+                (27_000, Cue::Warning(3)),
+                (28_000, Cue::Warning(2)),
+                (29_000, Cue::Warning(1)),
+                (30_000, Cue::Enter(1))
+            ]
+        );
+        engine.adjust(&items, 5000, &clock);
+        assert_eq!(
+            engine.upcoming(&items, 60_000)[0],
+            (32_000, Cue::Warning(3))
+        );
+        engine.go(&clock);
+        assert!(engine.upcoming(&items, 30_000).is_empty());
+    }
+
+    #[test]
+    pub fn progress() {
+        let items = vec![item("Work", 30), item("rest", 10)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.go(&clock);
+        clock.advance(15_000);
+        engine.tick(&items, &clock);
+        assert_eq!(
+            engine.progress(&items),
+            Progress {
+                item: Some(0.5),
+                done: 15_000,
+                total: 40_000
+            }
+        );
+        engine.adjust(&items, 30_000, &clock);
+        assert_eq!(engine.progress(&items).item, Some(0.25));
+        assert_eq!(engine.progress(&items).total, 70_000);
+    }
+
+    #[test]
+    pub fn beats_are_planned() {
+        use crate::workout::Cadence;
+        let mut steps = item("Step ups", 2);
+        steps.cadence = Some(Cadence {
+            bpm: 120,
+            accent: 2,
+        });
+        let items = vec![steps, item("rest", 10)];
+        let clock = VirtualClock(Cell::new(0));
+        let mut engine = Engine::new(&clock);
+        engine.warning = 0;
+        engine.go(&clock);
+        engine.tick(&items, &clock);
+        assert_eq!(
+            engine.upcoming(&items, 5000),
+            vec![
+                (500, Cue::Beat(0, 1)),
+                (1000, Cue::Beat(0, 2)),
+                (1500, Cue::Beat(0, 3)),
+                (2000, Cue::Enter(1))
+            ]
+        );
+        assert!(run(&mut engine, &items, &clock, 10, 2500)
+            .iter()
+            .all(|(_, e)| !matches!(e, Effect::Cue(Cue::Beat(..)))));
+    }
+}
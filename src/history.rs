@@ -0,0 +1,487 @@
+//! Records of past sessions, and statistics over them. Keeping them somewhere
+//! is up to the caller; this is all plain data.
+use crate::engine::{RunningState, Session};
+use crate::workout::FlatStatus;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ItemRecord {
+    pub name: String,
+    /// Millis, as written in the routine.
+    pub planned: i64,
+    /// Millis spent on the item, pauses included. Zero if it was skipped.
+    pub actual: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SessionRecord {
+    pub routine: String,
+    pub routine_hash: String,
+    /// Millis since the epoch.
+    pub started: i64,
+    pub ended: i64,
+    /// False if the session was abandoned before the end.
+    pub completed: bool,
+    pub items: Vec<ItemRecord>,
+    pub pauses: usize,
+    pub skipped: usize,
+    pub adjusted: usize,
+    /// Devices in the room during the session.
+    pub participants: Vec<String>,
+}
+
+/// A session being recorded. It can be kept somewhere and picked up again,
+/// so that a reload part way through doesn't lose it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Recording {
+    started: i64,
+    routine: String,
+    routine_hash: String,
+    /// The routine's items, with no time spent on them yet.
+    items: Vec<ItemRecord>,
+    session: Session,
+    /// Each item reached, and when.
+    visits: Vec<(usize, i64)>,
+    participants: Vec<String>,
+    /// Already handed back as completed.
+    done: bool,
+    /// When it was last observed.
+    seen: i64,
+}
+
+/// How often a recording in progress is worth keeping again, in millis. A
+/// recording picked up after longer than this is taken to have ended when
+/// it was last kept, give or take.
+const KEEP_EVERY: i64 = 10_000;
+
+impl Recording {
+    fn to_record(&self, ended: i64, completed: bool) -> SessionRecord {
+        let mut items = self.items.clone();
+        let ends = self.visits.iter().skip(1).map(|v| v.1).chain(Some(ended));
+        for (&(ix, from), to) in self.visits.iter().zip(ends) {
+            if let Some(i) = items.get_mut(ix) {
+                i.actual += to - from;
+            }
+        }
+        // Only the counts are wanted, which don't need the items.
+        let summary = self.session.summary_at(&[], ended);
+        SessionRecord {
+            routine: self.routine.clone(),
+            routine_hash: self.routine_hash.clone(),
+            started: self.started,
+            ended,
+            completed,
+            items,
+            pauses: summary.pauses,
+            skipped: summary.skipped,
+            adjusted: summary.adjusted,
+            participants: self.participants.clone(),
+        }
+    }
+    /// The record of giving up on it at `now`, if there's anything to
+    /// record.
+    fn abandon(&self, now: i64) -> Option<SessionRecord> {
+        let ended = now.min(self.seen + KEEP_EVERY);
+        if self.done || ended <= self.started {
+            return None;
+        }
+        Some(self.to_record(ended, false))
+    }
+}
+
+/// What a recording needs to catch up on, short of cloning the session.
+fn key(session: &Session) -> (usize, usize, Option<i64>) {
+    (
+        session.routine_ix,
+        session.log.len(),
+        session.log.last().map(|e| e.at),
+    )
+}
+
+/// Follows the session as it runs, noting when each item was reached, and
+/// hands back a record once it finishes or is abandoned.
+pub struct Recorder {
+    me: Option<String>,
+    current: Option<Recording>,
+    /// When `current` was last handed out to be kept.
+    kept: i64,
+    /// Whether `current` has moved on since.
+    changed: bool,
+}
+
+impl Recorder {
+    pub fn new(me: Option<String>) -> Self {
+        Self {
+            me,
+            current: None,
+            kept: 0,
+            changed: false,
+        }
+    }
+    /// Carry on with a recording kept from before a reload.
+    pub fn resume(&mut self, recording: Recording) {
+        self.current = Some(recording);
+    }
+    /// The recording in progress, if any.
+    pub fn recording(&self) -> Option<&Recording> {
+        self.current.as_ref()
+    }
+    /// Whether the recording (or the lack of one) should be kept again:
+    /// as soon as it changes, and every so often as it runs.
+    pub fn unkept(&mut self, now: i64) -> bool {
+        let due = self.changed || (self.current.is_some() && now - self.kept >= KEEP_EVERY);
+        if due {
+            self.kept = now;
+            self.changed = false;
+        }
+        due
+    }
+    /// Stop recording, say on leaving the room, handing back the session
+    /// so far as abandoned.
+    pub fn finish(&mut self, now: i64) -> Option<SessionRecord> {
+        self.changed = true;
+        self.current.take()?.abandon(now)
+    }
+    /// Someone else in the room is taking part.
+    pub fn saw(&mut self, participant: String) {
+        if let Some(r) = &mut self.current {
+            if !r.participants.contains(&participant) {
+                r.participants.push(participant);
+                self.changed = true;
+            }
+        }
+    }
+    pub fn observe(
+        &mut self,
+        session: &Session,
+        items: &[FlatStatus],
+        routine: &str,
+        routine_hash: &str,
+        now: i64,
+    ) -> Option<SessionRecord> {
+        let started = session.started();
+        let mut ans = None;
+        if self.current.as_ref().map(|r| r.started) != started {
+            ans = self.current.take().and_then(|old| old.abandon(now));
+            self.current = started.map(|started| Recording {
+                started,
+                routine: routine.to_owned(),
+                routine_hash: routine_hash.to_owned(),
+                items: items
+                    .iter()
+                    .map(|i| ItemRecord {
+                        name: i.name.clone(),
+                        planned: i.duration.unwrap_or(0) as i64 * 1000,
+                        actual: 0,
+                    })
+                    .collect(),
+                session: session.clone(),
+                visits: Vec::new(),
+                participants: self.me.iter().cloned().collect(),
+                done: false,
+                seen: now,
+            });
+            self.changed = true;
+        }
+        let r = match &mut self.current {
+            Some(r) if !r.done && now >= r.started => r,
+            _ => return ans,
+        };
+        r.seen = now;
+        if key(&r.session) != key(session) {
+            r.session = session.clone();
+        }
+        if r.visits.last().map(|v| v.0) != Some(session.routine_ix) {
+            r.visits.push((session.routine_ix, now));
+            self.changed = true;
+        }
+        if let RunningState::Finished(at) = session.state {
+            r.done = true;
+            self.changed = true;
+            ans = Some(r.to_record(at, true));
+        }
+        ans
+    }
+}
+
+/// `tz_offset_minutes` is UTC minus local time, as JS's
+/// `Date.getTimezoneOffset` gives it.
+pub fn local_time(millis: i64, tz_offset_minutes: i64) -> NaiveDateTime {
+    Utc.timestamp_millis_opt(millis - tz_offset_minutes * 60_000)
+        .unwrap()
+        .naive_utc()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeekTotal {
+    /// ISO week, like `2020-W37`.
+    pub week: String,
+    pub sessions: usize,
+    /// Millis spent working out.
+    pub millis: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RoutineCount {
+    /// As last recorded. Different routines can share a name.
+    pub name: String,
+    pub completed: usize,
+    pub abandoned: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    /// Most recent first.
+    pub weeks: Vec<WeekTotal>,
+    /// Days in a row, up to today, with a completed session. Today doesn't
+    /// break the streak until it's over.
+    pub current_streak: usize,
+    pub longest_streak: usize,
+    /// By routine hash.
+    pub routines: BTreeMap<String, RoutineCount>,
+}
+
+pub fn stats(records: &[SessionRecord], now: i64, tz_offset_minutes: i64) -> Stats {
+    let mut weeks: BTreeMap<(i32, u32), WeekTotal> = BTreeMap::new();
+    let mut days = BTreeSet::new();
+    let mut routines: BTreeMap<String, RoutineCount> = BTreeMap::new();
+    for r in records {
+        let date = local_time(r.started, tz_offset_minutes).date();
+        let iso = date.iso_week();
+        let week = weeks
+            .entry((iso.year(), iso.week()))
+            .or_insert_with(|| WeekTotal {
+                week: format!("{}-W{:02}", iso.year(), iso.week()),
+                sessions: 0,
+                millis: 0,
+            });
+        week.sessions += 1;
+        week.millis += r.ended - r.started;
+        let count = routines.entry(r.routine_hash.clone()).or_default();
+        count.name = r.routine.clone();
+        if r.completed {
+            count.completed += 1;
+            days.insert(date);
+        } else {
+            count.abandoned += 1;
+        }
+    }
+    let mut longest_streak = 0;
+    let mut run = 0;
+    let mut prev: Option<NaiveDate> = None;
+    for &d in &days {
+        run = if prev.and_then(|p| p.succ_opt()) == Some(d) {
+            run + 1
+        } else {
+            1
+        };
+        longest_streak = longest_streak.max(run);
+        prev = Some(d);
+    }
+    let today = local_time(now, tz_offset_minutes).date();
+    let mut day = if days.contains(&today) {
+        today
+    } else {
+        today.pred_opt().unwrap()
+    };
+    let mut current_streak = 0;
+    while days.contains(&day) {
+        current_streak += 1;
+        day = day.pred_opt().unwrap();
+    }
+    Stats {
+        weeks: weeks.into_iter().rev().map(|(_, w)| w).collect(),
+        current_streak,
+        longest_streak,
+        routines,
+    }
+}
+
+pub fn to_json(records: &[SessionRecord]) -> String {
+    serde_json::to_string_pretty(records).unwrap_or_default()
+}
+
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    started: String,
+    routine: &'a str,
+    routine_hash: &'a str,
+    completed: bool,
+    planned_secs: i64,
+    actual_secs: i64,
+    pauses: usize,
+    skipped: usize,
+    adjusted: usize,
+    participants: usize,
+}
+
+/// One row per session; the per-item detail is only in the JSON.
+pub fn to_csv(records: &[SessionRecord]) -> Result<String, String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for r in records {
+        writer
+            .serialize(CsvRow {
+                started: Utc.timestamp_millis_opt(r.started).unwrap().to_rfc3339(),
+                routine: &r.routine,
+                routine_hash: &r.routine_hash,
+                completed: r.completed,
+                planned_secs: r.items.iter().map(|i| i.planned).sum::<i64>() / 1000,
+                actual_secs: (r.ended - r.started) / 1000,
+                pauses: r.pauses,
+                skipped: r.skipped,
+                adjusted: r.adjusted,
+                participants: r.participants.len(),
+            })
+            .map_err(|e| format!("{}", e))?;
+    }
+    let bytes = writer.into_inner().map_err(|e| format!("{}", e))?;
+    String::from_utf8(bytes).map_err(|e| format!("{}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::{Clock, Engine};
+
+    struct At(i64);
+    impl Clock for At {
+        fn now(&self) -> i64 {
+            self.0
+        }
+    }
+    fn items() -> Vec<FlatStatus> {
+        ["Work", "rest"]
+            .iter()
+            .zip(&[2, 1])
+            .map(|(&name, &d)| FlatStatus::new(name, d))
+            .collect()
+    }
+    fn record(started: i64, completed: bool) -> SessionRecord {
+        SessionRecord {
+            routine: "test".into(),
+            routine_hash: "abc".into(),
+            started,
+            ended: started + 60_000,
+            completed,
+            items: Vec::new(),
+            pauses: 0,
+            skipped: 0,
+            adjusted: 0,
+            participants: Vec::new(),
+        }
+    }
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+
+    #[test]
+    pub fn records_a_finished_session() {
+        let items = items();
+        let mut engine = Engine::new(&At(0));
+        let mut recorder = Recorder::new(Some("me".into()));
+        engine.go(&At(0));
+        let mut saved = Vec::new();
+        for t in (0..=5000).step_by(100) {
+            engine.tick(&items, &At(t));
+            saved.extend(recorder.observe(&engine.session, &items, "test", "abc", t));
+        }
+        assert_eq!(saved.len(), 1);
+        let r = &saved[0];
+        assert!(r.completed);
+        assert_eq!((r.started, r.ended), (0, 3000));
+        assert_eq!(
+            r.items.iter().map(|i| i.actual).collect::<Vec<_>>(),
+            vec![2000, 1000]
+        );
+        assert_eq!(r.participants, vec!["me".to_owned()]);
+    }
+    #[test]
+    pub fn records_an_abandoned_session() {
+        let items = items();
+        let mut engine = Engine::new(&At(0));
+        let mut recorder = Recorder::new(None);
+        engine.go(&At(0));
+        assert_eq!(
+            recorder.observe(&engine.session, &items, "test", "abc", 0),
+            None
+        );
+        engine.tick(&items, &At(1500));
+        engine.go(&At(1500));
+        assert_eq!(
+            recorder.observe(&engine.session, &items, "test", "abc", 1500),
+            None
+        );
+        engine.to_config();
+        let r = recorder
+            .observe(&engine.session, &items, "test", "abc", 1800)
+            .unwrap();
+        assert!(!r.completed);
+        assert_eq!(r.pauses, 1);
+        assert_eq!(r.items[0].actual, 1800);
+    }
+    #[test]
+    pub fn picks_up_after_a_reload() {
+        let items = items();
+        let mut engine = Engine::new(&At(0));
+        let mut recorder = Recorder::new(Some("me".into()));
+        engine.go(&At(0));
+        recorder.observe(&engine.session, &items, "test", "abc", 0);
+        engine.tick(&items, &At(1000));
+        recorder.observe(&engine.session, &items, "test", "abc", 1000);
+        assert!(recorder.unkept(1000));
+        assert!(!recorder.unkept(1100));
+        let kept: Recording =
+            serde_json::from_str(&serde_json::to_string(recorder.recording().unwrap()).unwrap())
+                .unwrap();
+        // Back a minute later, and the session has gone.
+        let mut recorder = Recorder::new(Some("me".into()));
+        recorder.resume(kept);
+        engine.to_config();
+        let r = recorder
+            .observe(&engine.session, &items, "test", "abc", 61_000)
+            .unwrap();
+        assert!(!r.completed);
+        assert_eq!(r.ended, 1000 + KEEP_EVERY);
+        assert_eq!(recorder.finish(61_000), None);
+    }
+    #[test]
+    pub fn streaks_and_weeks() {
+        // 2020-09-07 was a Monday.
+        let monday = 1_599_436_800_000 + 12 * 60 * 60 * 1000;
+        let mut records = vec![
+            record(monday, true),
+            record(monday + DAY, true),
+            record(monday + DAY, false),
+            record(monday + 3 * DAY, true),
+            record(monday + 4 * DAY, true),
+            record(monday + 7 * DAY, true),
+        ];
+        let mut other = record(monday, true);
+        other.routine_hash = "def".into();
+        records.push(other);
+        let s = stats(&records, monday + 7 * DAY, 0);
+        assert_eq!(s.longest_streak, 2);
+        assert_eq!(s.current_streak, 1);
+        assert_eq!(s.weeks[0].week, "2020-W38");
+        assert_eq!(s.weeks[1].sessions, 6);
+        assert_eq!(
+            s.routines["abc"],
+            RoutineCount {
+                name: "test".into(),
+                completed: 5,
+                abandoned: 1
+            }
+        );
+        assert_eq!(s.routines["def"].completed, 1);
+    }
+    #[test]
+    pub fn csv_export() {
+        let csv = to_csv(&[record(0, true)]).unwrap();
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().starts_with("started,routine,"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("1970-01-01T00:00:00+00:00,test,abc,true,"));
+    }
+}
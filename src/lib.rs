@@ -1,10 +1,21 @@
 use seed::{prelude::*, *};
 use serde::{Deserialize, Serialize};
 use web_sys;
-use web_sys::AudioContext;
 
+mod audio;
+mod circuit;
+mod engine;
+mod history;
 mod mqtt_websocket;
+mod roles;
 mod routine;
+mod scheduler;
+mod share;
+mod sheet;
+mod sound;
+mod speech;
+mod synth;
+mod timers;
 mod workout;
 
 use serde_json::Value;
@@ -36,8 +47,9 @@ enum Page {
 pub struct Context {
     server_time_delta: i64,
     server_deltas: Vec<i64>,
-    audio_ctx: Option<AudioContext>,
-    mqtt_connection: Option<mqtt_websocket::Model<crate::pages::workout::PublishedModel>>,
+    audio: Option<audio::Audio>,
+    role_id: Ulid,
+    mqtt_connection: Option<mqtt_websocket::Model<crate::pages::workout::RoomMsg>>,
 }
 
 impl Context {
@@ -46,49 +58,170 @@ impl Context {
         let offset = Duration::milliseconds(self.server_time_delta);
         local + offset
     }
-    pub fn beep(&self, duration: f64, frequency: f32) -> Option<()> {
-        if let Some(ctx) = &self.audio_ctx {
-            let osc = ctx.create_oscillator().ok()?;
-            let gain = ctx.create_gain().ok()?;
-            osc.connect_with_audio_node(&gain).ok()?;
-            gain.connect_with_audio_node(&ctx.destination()).ok()?;
-            osc.frequency().set_value(frequency);
-            let now = self.audio_ctx.as_ref().unwrap().current_time();
-            osc.start().ok()?;
-            osc.stop_with_when(now + duration).ok()?;
-            Some(())
+    /// Says `utterance` after `delay` millis.
+    pub fn say(
+        &self,
+        utterance: &speech::Utterance,
+        prefs: &speech::SpeechPrefs,
+        delay: i32,
+    ) -> Option<()> {
+        let synth = window().speech_synthesis().ok()?;
+        let u = web_sys::SpeechSynthesisUtterance::new_with_text(&utterance.text).ok()?;
+        u.set_lang(&prefs.lang);
+        if let Some(v) = self
+            .voices(&prefs.lang)
+            .into_iter()
+            .find(|v| Some(v.name()) == prefs.voice)
+        {
+            u.set_voice(Some(&v));
+        }
+        if utterance.interrupt {
+            synth.cancel();
+        }
+        if delay <= 0 {
+            synth.speak(&u);
         } else {
-            None
+            let later = Closure::once_into_js(move || synth.speak(&u));
+            window()
+                .set_timeout_with_callback_and_timeout_and_arguments_0(later.unchecked_ref(), delay)
+                .ok()?;
+        }
+        Some(())
+    }
+    /// Voices the browser has for a language (or any, for an empty `lang`).
+    pub fn voices(&self, lang: &str) -> Vec<web_sys::SpeechSynthesisVoice> {
+        window()
+            .speech_synthesis()
+            .map(|s| {
+                s.get_voices()
+                    .iter()
+                    .map(|v| v.unchecked_into::<web_sys::SpeechSynthesisVoice>())
+                    .filter(|v| v.lang().starts_with(lang))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    /// Plays the sound straight away.
+    pub fn play(&self, sound: &sound::Sound) {
+        if let Some(a) = &self.audio {
+            a.play(sound);
+        }
+    }
+    /// How long the sound takes to play, in millis.
+    pub fn sound_millis(&self, sound: &sound::Sound) -> i32 {
+        self.audio
+            .as_ref()
+            .map_or(0, |a| (a.duration(sound) * 1000.) as i32)
+    }
+    pub fn load_sample(&self, name: String, bytes: &[u8]) {
+        if let Some(a) = &self.audio {
+            a.load(name, bytes);
+        }
+    }
+    pub fn unload_sample(&self, name: &str) {
+        if let Some(a) = &self.audio {
+            a.unload(name);
+        }
+    }
+    /// Queues the cues coming up, and calls off any that aren't any more.
+    pub fn schedule(&self, plan: Vec<(scheduler::Planned, sound::Sound)>) {
+        if let Some(a) = &self.audio {
+            a.schedule(plan, engine::Clock::now(self));
+        }
+    }
+    /// Whether `cue` was queued ahead, and so has been heard already.
+    pub fn queued(&self, cue: engine::Cue) -> bool {
+        self.audio
+            .as_ref()
+            .map_or(false, |a| a.take(cue, engine::Clock::now(self)))
+    }
+    pub fn set_volume(&self, volume: f32) {
+        if let Some(a) = &self.audio {
+            a.set_volume(volume);
         }
     }
-    pub fn announce(&self, model: &crate::pages::workout::PublishedModel) {
+    /// Who we are when it comes to roles, the same on every connection.
+    pub fn role_id(&self) -> Ulid {
+        self.role_id
+    }
+    pub fn send(&self, msg: &crate::pages::workout::RoomMsg) {
         if let Some(x) = &self.mqtt_connection {
-            x.send_msg(model)
+            x.send_msg(msg)
         }
     }
 }
+impl engine::Clock for Context {
+    fn now(&self) -> i64 {
+        self.current_time().timestamp_millis()
+    }
+}
 struct Model {
     page: Page,
+    /// Shown over the page, which carries on underneath.
+    history: Option<pages::history::Model>,
     login: pages::login::Model,
     context: Context,
+    base_url: Url,
+    shared: Option<share::SharedLink>,
 }
 const TOPIC_PREFIX: &str = "/xcvyunaizrsemkt/timer-app/test";
+const DEVICE_KEY: &str = "timer-device-id";
+
+/// Who we are when it comes to roles in rooms. Kept for good, so that the
+/// owner is still the owner after closing the tab; tabs in the same browser
+/// share it.
+fn role_id() -> Ulid {
+    if let Ok(id) = LocalStorage::get(DEVICE_KEY) {
+        return id;
+    }
+    let id = Ulid::new();
+    if let Err(e) = LocalStorage::insert(DEVICE_KEY, &id) {
+        error!("Failed to save device id", e);
+    }
+    id
+}
 
 impl Default for Model {
     fn default() -> Self {
         Self {
             page: Page::Login,
+            history: None,
             login: pages::login::Model::init(),
             context: Context {
-                audio_ctx: web_sys::AudioContext::new().ok(),
+                audio: audio::Audio::new(),
+                role_id: role_id(),
                 mqtt_connection: None,
                 server_time_delta: 0,
                 server_deltas: Vec::new(),
             },
+            base_url: Url::new(),
+            shared: None,
+        }
+    }
+}
+impl Model {
+    /// Picks up a share link from the url, if there is one. Links naming a
+    /// room join it straight away.
+    fn handle_url(&mut self, mut url: Url, orders: &mut impl Orders<Msg>) {
+        if url.next_path_part() != Some("config") {
+            return;
+        }
+        match url.hash().map(|h| share::SharedLink::from_fragment(h)) {
+            Some(Ok(link)) => match (&mut self.page, &link.room) {
+                (Page::Workout(w), None) => w.load_shared(link.config, &self.context),
+                (_, room) => {
+                    if let Some(r) = room {
+                        self.login.room = r.to_owned();
+                        orders.notify(subs::Event::Connect);
+                    }
+                    self.shared = Some(link);
+                }
+            },
+            Some(Err(e)) => error!(e),
+            None => {}
         }
     }
 }
-impl Model {}
 
 // ------ ------
 //    Update
@@ -98,6 +231,7 @@ impl Model {}
 enum AppMsg {
     LoginMsg(pages::login::Msg),
     WorkoutMsg(pages::workout::Msg),
+    HistoryMsg(pages::history::Msg),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -107,13 +241,22 @@ struct MqttMsg {
 }
 enum Msg {
     InternalMsg(AppMsg),
-    ExternalMsg(mqtt_websocket::ReceivedMsg<crate::pages::workout::PublishedModel>),
+    ExternalMsg(mqtt_websocket::ReceivedMsg<crate::pages::workout::RoomMsg>),
     MqttMsg(mqtt_websocket::Msg),
     Rendered(RenderInfo),
+    /// Keeps time while the tab is hidden and not rendering.
+    Tick,
     HandleEvent(subs::Event),
     SetServerDelta(i64),
+    Subscribed(mqtt_websocket::Subscribed),
+    KeyDown(web_sys::KeyboardEvent),
+    UrlChanged(seed::app::subs::UrlChanged),
 }
 fn update_app(msg: AppMsg, model: &mut Model, orders: &mut impl Orders<AppMsg>) {
+    if let (Some(h), AppMsg::HistoryMsg(m)) = (&mut model.history, &msg) {
+        pages::history::update(m.clone(), h, &mut orders.proxy(AppMsg::HistoryMsg));
+        return;
+    }
     match (&mut model.page, &msg) {
         (Page::Login, AppMsg::LoginMsg(m)) => pages::login::update(
             m.clone(),
@@ -138,7 +281,7 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         Msg::ExternalMsg(msg2) => {
             if let Page::Workout(m) = &mut model.page {
                 crate::pages::workout::update(
-                    crate::pages::workout::Msg::ExternalUpdate(msg2.msg),
+                    crate::pages::workout::Msg::FromRoom(msg2.msg),
                     m,
                     &mut orders.proxy(Msg::InternalMsg).proxy(AppMsg::WorkoutMsg),
                     &model.context,
@@ -152,26 +295,45 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         }
         Msg::Rendered(_) => {
             if let Page::Workout(x) = &mut model.page {
-                for ev in x.time_fn(&model.context) {
-                    orders.notify(ev);
-                }
+                x.time_fn(&model.context);
             }
             orders.after_next_render(Msg::Rendered);
         }
+        Msg::Tick => {
+            if let Page::Workout(x) = &mut model.page {
+                x.time_fn(&model.context);
+            }
+        }
         Msg::HandleEvent(e) => match e {
             subs::Event::Connect => {
+                model.login.save();
                 model.context.mqtt_connection = Some(mqtt_websocket::Model::new(
                     "wss://test.mosquitto.org:8081/mqtt",
                     &format!("{}/{}", TOPIC_PREFIX, &model.login.room),
                     &model.login.password,
                 ));
                 mqtt_websocket::connect(&mut orders.proxy(Msg::MqttMsg));
-                model.page = Page::Workout(crate::pages::workout::Model::init(&model.context));
+                let mut workout = crate::pages::workout::Model::init(
+                    &model.context,
+                    model.base_url.clone(),
+                    model.login.room.clone(),
+                );
+                if let Some(link) = model.shared.take() {
+                    workout.load_shared(link.config, &model.context);
+                } else if let Some(saved) = pages::workout::saved_session() {
+                    workout.restore(saved);
+                }
+                model.page = Page::Workout(workout);
             }
             subs::Event::Disconnect => {
+                pages::workout::forget_session();
                 model.context.mqtt_connection = None;
                 model.page = Page::Login;
             }
+            subs::Event::ShowHistory => {
+                model.history = Some(pages::history::Model::init(&model.context));
+            }
+            subs::Event::HideHistory => model.history = None,
         },
         Msg::SetServerDelta(d) => {
             model.context.server_deltas.push(d);
@@ -183,6 +345,35 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                 log!(model.context.server_time_delta);
             }
         }
+        Msg::KeyDown(e) => {
+            let typing = e
+                .target()
+                .and_then(|t| t.dyn_into::<web_sys::Element>().ok())
+                .map_or(false, |t| {
+                    matches!(t.tag_name().as_str(), "INPUT" | "TEXTAREA")
+                });
+            let msg = match &model.page {
+                _ if model.history.is_some() => match e.key().as_str() {
+                    "Escape" => Some(AppMsg::HistoryMsg(pages::history::Msg::Close)),
+                    _ => None,
+                },
+                Page::Workout(w) => {
+                    pages::workout::key_msg(w, &e.key(), e.ctrl_key() || e.meta_key(), typing)
+                        .map(AppMsg::WorkoutMsg)
+                }
+                Page::Login => None,
+            };
+            if let Some(m) = msg {
+                e.prevent_default();
+                orders.send_msg(Msg::InternalMsg(m));
+            }
+        }
+        Msg::Subscribed(_) => {
+            if let Page::Workout(w) = &mut model.page {
+                w.rejoined(&model.context);
+            }
+        }
+        Msg::UrlChanged(seed::app::subs::UrlChanged(url)) => model.handle_url(url, orders),
     }
 }
 
@@ -207,12 +398,28 @@ async fn request_time() -> Option<Msg> {
     Some(Msg::SetServerDelta(diff.num_milliseconds()))
 }
 
-fn after_mount(_: Url, orders: &mut impl Orders<Msg>) -> AfterMount<Model> {
+fn after_mount(url: Url, orders: &mut impl Orders<Msg>) -> AfterMount<Model> {
     orders.perform_cmd(request_time());
     orders.after_next_render(Msg::Rendered);
     orders.subscribe(Msg::ExternalMsg);
     orders.subscribe(Msg::HandleEvent);
-    AfterMount::default()
+    orders.subscribe(Msg::UrlChanged);
+    orders.subscribe(Msg::Subscribed);
+    orders.stream(streams::interval(1000, || Msg::Tick));
+    orders.stream(streams::window_event(Ev::KeyDown, |e| {
+        Msg::KeyDown(e.unchecked_into())
+    }));
+    let mut model = Model::default();
+    model.base_url = url.to_base_url();
+    let (login, remembered) = pages::login::Model::restore();
+    model.login = login;
+    model.handle_url(url, orders);
+    // Reloaded part way through: go straight back in.
+    let in_room = model.shared.as_ref().map_or(false, |l| l.room.is_some());
+    if remembered && !in_room && pages::workout::saved_session().is_some() {
+        orders.notify(subs::Event::Connect);
+    }
+    AfterMount::new(model)
 }
 
 // ------ ------
@@ -220,6 +427,9 @@ fn after_mount(_: Url, orders: &mut impl Orders<Msg>) -> AfterMount<Model> {
 // ------ ------
 
 fn view_app(model: &Model) -> Node<AppMsg> {
+    if let Some(h) = &model.history {
+        return pages::history::view(h).map_msg(AppMsg::HistoryMsg);
+    }
     match &model.page {
         Page::Login => pages::login::view(&model.login).map_msg(AppMsg::LoginMsg),
         Page::Workout(m) => pages::workout::view(m).map_msg(AppMsg::WorkoutMsg),
@@ -24,7 +24,12 @@ where
     T: Clone,
 {
     pub msg: T,
+    pub sender: Ulid,
 }
+/// Notified once we're subscribed to the topic, on first connecting and
+/// after any reconnect.
+#[derive(Clone)]
+pub struct Subscribed;
 #[derive(Serialize, Deserialize, Debug)]
 struct MqttWrap {
     msg: String,
@@ -170,7 +175,10 @@ where
                         if as_mqtt_wrap.sender != my_id {
                             let as_t: T = serde_json::from_str(&as_mqtt_wrap.msg).unwrap();
                             log!("Decoded mqtt message", as_t);
-                            orders.notify(ReceivedMsg { msg: as_t });
+                            orders.notify(ReceivedMsg {
+                                msg: as_t,
+                                sender: as_mqtt_wrap.sender,
+                            });
                         } else {
                             log!("ignored incoming message from self");
                         }
@@ -184,7 +192,9 @@ where
                     }
                     packet::VariablePacket::PingrespPacket(_) => {}
                     packet::VariablePacket::SubscribePacket(_) => {}
-                    packet::VariablePacket::SubackPacket(_) => {}
+                    packet::VariablePacket::SubackPacket(_) => {
+                        orders.notify(Subscribed);
+                    }
                     packet::VariablePacket::UnsubscribePacket(_) => {}
                     packet::VariablePacket::UnsubackPacket(_) => {}
                     packet::VariablePacket::DisconnectPacket(_) => {}
@@ -0,0 +1,146 @@
+use super::data_url;
+use crate::engine::Clock;
+use crate::history::{self, SessionRecord};
+use crate::workout::timer;
+use seed::{prelude::*, *};
+use serde::{Deserialize, Serialize};
+
+const STORAGE_KEY: &str = "timer-history";
+
+fn load_records() -> Vec<SessionRecord> {
+    LocalStorage::get(STORAGE_KEY).unwrap_or_default()
+}
+/// Add a finished (or abandoned) session to this browser's history.
+pub fn save_record(record: SessionRecord) {
+    let mut records = load_records();
+    records.push(record);
+    if let Err(e) = LocalStorage::insert(STORAGE_KEY, &records) {
+        error!("Failed to save session history", e);
+    }
+}
+
+pub struct Model {
+    records: Vec<SessionRecord>,
+    now: i64,
+    tz_offset: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Msg {
+    Close,
+}
+
+impl Model {
+    pub fn init(context: &crate::Context) -> Self {
+        Self {
+            records: load_records(),
+            now: context.now(),
+            tz_offset: js_sys::Date::new_0().get_timezone_offset() as i64,
+        }
+    }
+}
+pub fn update(msg: Msg, _model: &mut Model, orders: &mut impl Orders<Msg>) {
+    match msg {
+        Msg::Close => {
+            orders.notify(crate::subs::Event::HideHistory);
+        }
+    }
+}
+
+fn mins(millis: i64) -> String {
+    timer(millis / 1000)
+}
+fn view_stats(model: &Model) -> Node<Msg> {
+    let stats = history::stats(&model.records, model.now, model.tz_offset);
+    div![
+        class! {"stats"},
+        p![format!(
+            "Current streak: {} days. Longest streak: {} days.",
+            stats.current_streak, stats.longest_streak
+        )],
+        table![
+            tr![th!["Week"], th!["Sessions"], th!["Time"]],
+            stats.weeks.iter().map(|w| tr![
+                td![&w.week],
+                td![w.sessions.to_string()],
+                td![mins(w.millis)]
+            ])
+        ],
+        table![
+            tr![th!["Routine"], th!["Completed"], th!["Abandoned"]],
+            stats.routines.iter().map(|(hash, c)| tr![
+                td![
+                    attrs! {At::Title => hash},
+                    // Tell apart different routines with the same name.
+                    if stats.routines.values().filter(|o| o.name == c.name).count() > 1 {
+                        format!("{} ({})", c.name, &hash[..hash.len().min(6)])
+                    } else {
+                        c.name.clone()
+                    }
+                ],
+                td![c.completed.to_string()],
+                td![c.abandoned.to_string()]
+            ])
+        ]
+    ]
+}
+fn view_record(model: &Model, r: &SessionRecord) -> Node<Msg> {
+    tr![
+        td![history::local_time(r.started, model.tz_offset)
+            .format("%Y-%m-%d %H:%M")
+            .to_string()],
+        td![&r.routine],
+        td![if r.completed {
+            "Completed"
+        } else {
+            "Abandoned"
+        }],
+        td![mins(r.ended - r.started)],
+        td![r.pauses.to_string()],
+        td![r.skipped.to_string()],
+        td![r.adjusted.to_string()],
+        td![r.participants.len().to_string()],
+    ]
+}
+pub fn view(model: &Model) -> Node<Msg> {
+    let csv = history::to_csv(&model.records).unwrap_or_else(|e| e);
+    div![
+        class! {"history"},
+        button!["Back", ev(Ev::Click, |_| Msg::Close)],
+        h1!["History"],
+        if model.records.is_empty() {
+            p!["No sessions yet."]
+        } else {
+            div![
+                view_stats(model),
+                div![
+                    class! {"export"},
+                    "Export: ",
+                    a![
+                        attrs! {At::Href => data_url("application/json", &history::to_json(&model.records)), At::Download => "history.json"},
+                        "JSON"
+                    ],
+                    " ",
+                    a![
+                        attrs! {At::Href => data_url("text/csv", &csv), At::Download => "history.csv"},
+                        "CSV"
+                    ]
+                ],
+                table![
+                    class! {"sessions"},
+                    tr![
+                        th!["Started"],
+                        th!["Routine"],
+                        th![],
+                        th!["Time"],
+                        th!["Pauses"],
+                        th!["Skipped"],
+                        th!["Adjusted"],
+                        th!["Devices"]
+                    ],
+                    model.records.iter().rev().map(|r| view_record(model, r))
+                ]
+            ]
+        }
+    ]
+}
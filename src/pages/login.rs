@@ -5,13 +5,24 @@ use serde::{Deserialize, Serialize};
 pub struct Model {
     pub room: String,
     pub password: String,
+    pub remember_password: bool,
+}
+
+const STORAGE_KEY: &str = "timer-login";
+
+#[derive(Serialize, Deserialize)]
+struct SavedLogin {
+    room: String,
+    password: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Msg {
     RoomChanged(String),
     PasswordChanged(String),
+    ToggleRememberPassword,
     Connect,
+    ShowHistory,
 }
 
 impl Model {
@@ -19,6 +30,34 @@ impl Model {
         Self {
             room: "ABCD".to_owned(),
             password: "1234".to_owned(),
+            remember_password: false,
+        }
+    }
+    /// The last room connected to, if any, and whether the password was
+    /// kept as well, so that we can go straight back in.
+    pub fn restore() -> (Self, bool) {
+        let mut m = Self::init();
+        if let Ok(saved) = LocalStorage::get::<SavedLogin>(STORAGE_KEY) {
+            m.room = saved.room;
+            if let Some(p) = saved.password {
+                m.password = p;
+                m.remember_password = true;
+            }
+        }
+        let remembered = m.remember_password;
+        (m, remembered)
+    }
+    pub fn save(&self) {
+        let saved = SavedLogin {
+            room: self.room.clone(),
+            password: if self.remember_password {
+                Some(self.password.clone())
+            } else {
+                None
+            },
+        };
+        if let Err(e) = LocalStorage::insert(STORAGE_KEY, &saved) {
+            error!("Failed to save login", e);
         }
     }
 }
@@ -30,9 +69,15 @@ pub fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         Msg::PasswordChanged(p) => {
             model.password = p;
         }
+        Msg::ToggleRememberPassword => {
+            model.remember_password = !model.remember_password;
+        }
         Msg::Connect => {
             orders.notify(crate::subs::Event::Connect);
         }
+        Msg::ShowHistory => {
+            orders.notify(crate::subs::Event::ShowHistory);
+        }
     }
 }
 pub fn view(model: &Model) -> Node<Msg> {
@@ -47,6 +92,14 @@ pub fn view(model: &Model) -> Node<Msg> {
             attrs! {At::Value => model.password},
             input_ev(Ev::Input, Msg::PasswordChanged)
         ],
-        button!["Connect", ev(Ev::Click, |_| Msg::Connect)]
+        label![
+            input![
+                attrs! {At::Type => "checkbox", At::Checked => model.remember_password.as_at_value()},
+                ev(Ev::Click, |_| Msg::ToggleRememberPassword)
+            ],
+            "Remember password"
+        ],
+        button!["Connect", ev(Ev::Click, |_| Msg::Connect)],
+        button!["History", ev(Ev::Click, |_| Msg::ShowHistory)]
     ]
 }
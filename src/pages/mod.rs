@@ -1,2 +1,12 @@
+pub mod history;
 pub mod login;
 pub mod workout;
+
+/// A link target holding `content` itself, for downloads.
+pub fn data_url(mime: &str, content: &str) -> String {
+    format!(
+        "data:{};charset=utf-8,{}",
+        mime,
+        js_sys::encode_uri_component(content)
+    )
+}
@@ -1,135 +1,661 @@
-use crate::{routine, workout::FlatStatus};
+use super::{data_url, history::save_record};
+use crate::circuit::{self, Circuit};
+use crate::engine::{self, Clock, Cue, Effect, Engine, RunningState};
+use crate::roles::{self, Roster};
+use crate::scheduler::{self, Planned};
+use crate::sound::{self, SoundPrefs, SoundProfile};
+use crate::speech::{self, SpeechPrefs};
+use crate::synth;
+use crate::timers::{self, Route};
+use crate::{history, routine, share, sheet, workout::FlatStatus};
 use seed::{prelude::*, *};
+use std::collections::{BTreeMap, BTreeSet};
 
-use chrono::Duration;
-use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PublishedModel {
+    /// Which of the room's timers this is; empty for the room's first.
+    #[serde(default)]
+    timer: String,
     config: String,
-    state: RunningState,
-    routine_ix: usize,
+    /// Routines to run after this one.
+    #[serde(default)]
+    queue: Vec<String>,
+    /// When this last changed, by the room's clock, so that a device coming
+    /// back from a reload can tell whether the room has moved on.
+    #[serde(default)]
+    updated: i64,
+    #[serde(default)]
+    circuit: Circuit,
+    #[serde(default)]
+    roster: Roster,
+    /// The role id of the device which sent this.
+    #[serde(default)]
+    from: String,
+    #[serde(flatten)]
+    session: engine::Session,
 }
-pub struct Model {
+
+/// What goes between the devices in a room.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum RoomMsg {
+    Update(PublishedModel),
+    /// Who's here, by role id, and which timer they follow. Sent every so
+    /// often, so that devices which never send updates, like displays, can
+    /// still be found and given a role.
+    Hello {
+        from: String,
+        timer: String,
+    },
+}
+
+impl PublishedModel {
+    fn header(&self) -> timers::Header<'_> {
+        timers::Header {
+            timer: &self.timer,
+            updated: self.updated,
+            from: &self.from,
+            roster: &self.roster,
+        }
+    }
+}
+
+const STORAGE_KEY: &str = "timer-session";
+
+/// What's kept in local storage to carry on after a reload.
+#[derive(Serialize, Deserialize)]
+pub struct SavedSession {
     published: PublishedModel,
+    /// Of the compiled routine, as the session's item indexes only make
+    /// sense against the same one.
+    compiled_hash: String,
+}
+pub fn saved_session() -> Option<SavedSession> {
+    LocalStorage::get(STORAGE_KEY).ok()
+}
+pub fn forget_session() {
+    LocalStorage::remove(STORAGE_KEY).ok();
+}
+
+/// The session being recorded for the history page, so that a reload
+/// doesn't lose it.
+const RECORDING_KEY: &str = "timer-recording";
+
+const PREFS_KEY: &str = "timer-sound-prefs";
+const SPEECH_KEY: &str = "timer-speech-prefs";
+/// Audio files loaded on this device, base64 encoded, by name.
+const SAMPLES_KEY: &str = "timer-samples";
+
+fn stored_samples() -> BTreeMap<String, String> {
+    LocalStorage::get(SAMPLES_KEY).unwrap_or_default()
+}
+fn store_samples(samples: &BTreeMap<String, String>) {
+    if let Err(e) = LocalStorage::insert(SAMPLES_KEY, samples) {
+        error!("Failed to save samples", e);
+    }
+}
+/// How long an item cue's label stays up.
+const FLASH_MILLIS: i64 = 2000;
+/// How often to say hello to the room.
+const HELLO_MILLIS: i64 = 30_000;
+/// How often to line up cues ahead when nothing has changed, so that the
+/// horizon moves on.
+const REPLAN_MILLIS: i64 = 1000;
+
+pub struct Model {
+    config: String,
+    queue: Vec<String>,
+    /// Why the next routine in the queue couldn't be started.
+    queue_error: Option<String>,
+    updated: i64,
+    engine: Engine,
     pub routine: Result<Vec<FlatStatus>, String>,
-    last_update: i64,
+    pub tree: Option<routine::RoutineNode>,
+    routine_hash: String,
+    routine_sounds: Option<SoundProfile>,
+    prefs: SoundPrefs,
+    /// What we're actually playing: the routine's sounds, or ours.
+    sounds: SoundProfile,
+    speech: SpeechPrefs,
+    /// Names of the voices there are for `speech.lang`.
+    voices: Vec<String>,
+    /// Names of the samples loaded on this device.
+    samples: Vec<String>,
+    recorder: history::Recorder,
+    /// Recorders of the timers we've stopped following, to carry on with if
+    /// we come back to them.
+    recorders: BTreeMap<String, history::Recorder>,
+    base_url: Url,
+    room: String,
+    /// The timer in the room we're following.
+    timer: String,
+    /// What we last heard of the room's other timers, by name.
+    others: BTreeMap<String, PublishedModel>,
+    circuit: Circuit,
+    roster: Roster,
+    /// Our role id in the room.
+    me: String,
+    /// Role ids of the devices we've heard from in the room, and when.
+    peers: BTreeMap<String, i64>,
+    /// When we last said hello; zero until we've joined.
+    greeted: i64,
+    /// The circuit station this device stands at, showing only that.
+    station: Option<usize>,
+    show_keys: bool,
+    /// Blocks of the workout list opened or closed by hand, by depth and
+    /// first item: the one we're in is open otherwise, and the rest closed.
+    toggled: BTreeSet<(usize, usize)>,
+    /// The label of an item's cue that just went off, and until when to
+    /// show it.
+    flash: Option<(String, i64)>,
+    /// When cues were last lined up ahead.
+    planned_at: i64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Msg {
     ChangeItem(usize),
     Go,
+    /// Seconds to add to the current item; negative to take some off.
+    Adjust(i64),
+    Undo,
+    /// Start everyone at once, this many seconds from now.
+    StartIn(i64),
+    /// Start everyone at once, at this local time (`HH:MM`).
+    StartAt(String),
     ToConfig,
     ConfigChanged(String),
+    /// Put the routine being edited on the end of the queue.
+    QueueConfig,
+    ClearQueue,
+    /// Take the next routine off the queue and start it.
+    StartNext,
+    ShowHistory,
+    /// Show or hide the list of keyboard shortcuts.
+    ToggleKeys,
+    /// Open or close a block of the workout list: its depth and first item.
+    ToggleBlock(usize, usize),
+    /// Silence this device.
+    ToggleMute,
+    /// Percent.
+    SetVolume(String),
+    /// Use this device's own sounds rather than the routine's.
+    ToggleOwnSounds,
+    SetWarning(String),
+    SetPattern(sound::Slot, String),
+    /// Play a sample rather than tones for the slot. Empty for tones.
+    SetSample(sound::Slot, String),
+    #[serde(skip)]
+    AddSample(web_sys::File),
+    SampleRead(String, Vec<u8>),
+    RemoveSample(String),
+    ToggleSpeech,
+    SetLang(String),
+    SetVoice(String),
+    SetTemplate(speech::Slot, String),
     Disconnect,
-    ExternalUpdate(PublishedModel),
+    /// Comma separated exercises, one for each circuit station.
+    SetStations(String),
+    /// Comma separated names of the groups going round the circuit.
+    SetGroups(String),
+    /// Show just this circuit station on this device. Empty for all.
+    ShowStation(String),
+    /// Give a device in the room a role: its id, and the role's name.
+    SetRole(String, String),
+    /// Follow another of the room's timers, or start a new one by this name.
+    FollowTimer(String),
+    /// Become the owner of a timer nobody owns yet.
+    ClaimRoom,
+    FromRoom(RoomMsg),
 }
-#[derive(Serialize, Deserialize, Clone, Debug)]
-enum RunningState {
-    RunningSince(i64),
-    PausedAfter(i64),
-    Config,
-}
-lazy_static! {
-    static ref END_STATUS: FlatStatus = FlatStatus {
-        name: "END".to_string(),
-        this_rep: 1,
-        total_reps: 1,
-        duration: None,
-    };
-}
-impl PublishedModel {
-    pub fn init() -> Self {
-        Self {
-            config: routine::SEVEN.to_owned(),
-            state: RunningState::Config,
-            routine_ix: 0,
-        }
+impl Msg {
+    /// Whether this runs the timer or changes the routine, which displays
+    /// aren't allowed to.
+    fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Msg::ChangeItem(_)
+                | Msg::Go
+                | Msg::Adjust(_)
+                | Msg::Undo
+                | Msg::StartIn(_)
+                | Msg::StartAt(_)
+                | Msg::ToConfig
+                | Msg::ConfigChanged(_)
+                | Msg::QueueConfig
+                | Msg::ClearQueue
+                | Msg::StartNext
+                | Msg::SetStations(_)
+                | Msg::SetGroups(_)
+                | Msg::ClaimRoom
+        )
     }
 }
+/// Whether a routine compiles all the way down to a list of items.
+fn check_config(config: &str) -> Result<(), String> {
+    routine::compile(config)?.to_full_workout().map(|_| ())
+}
+fn items(routine: &Result<Vec<FlatStatus>, String>) -> &[FlatStatus] {
+    routine.as_ref().map(|x| x.as_slice()).unwrap_or(&[])
+}
 impl Model {
-    pub fn init(context: &crate::Context) -> Self {
-        let now = context.current_time().timestamp_millis();
+    pub fn init(context: &crate::Context, base_url: Url, room: String) -> Self {
         let mut m = Self {
-            published: PublishedModel::init(),
+            config: routine::SEVEN.to_owned(),
+            queue: Vec::new(),
+            queue_error: None,
+            updated: 0,
+            engine: Engine::new(context),
             routine: Err("Not compiled yet".into()),
-            last_update: now,
+            tree: None,
+            routine_hash: String::new(),
+            routine_sounds: None,
+            prefs: LocalStorage::get(PREFS_KEY).unwrap_or_default(),
+            sounds: SoundProfile::default(),
+            speech: LocalStorage::get(SPEECH_KEY).unwrap_or_default(),
+            voices: Vec::new(),
+            samples: Vec::new(),
+            recorder: history::Recorder::new(Some(context.role_id().to_string())),
+            recorders: BTreeMap::new(),
+            base_url,
+            room,
+            timer: String::new(),
+            others: BTreeMap::new(),
+            circuit: Circuit::default(),
+            roster: Roster::default(),
+            me: context.role_id().to_string(),
+            peers: BTreeMap::new(),
+            greeted: 0,
+            station: None,
+            show_keys: false,
+            toggled: BTreeSet::new(),
+            flash: None,
+            planned_at: 0,
         };
-        m.routine = m.compile_config();
+        if let Ok(recording) = LocalStorage::get(RECORDING_KEY) {
+            m.recorder.resume(recording);
+        }
+        m.compile_config();
+        m.speech_changed(context);
+        m.set_volume(context);
+        for (name, data) in stored_samples() {
+            match base64::decode(&data) {
+                Ok(bytes) => context.load_sample(name.clone(), &bytes),
+                Err(e) => error!("Bad sample", name, e.to_string()),
+            }
+            m.samples.push(name);
+        }
         return m;
     }
-    fn compile_config(&self) -> Result<Vec<FlatStatus>, String> {
-        let full = routine::TYPES.to_owned() + &self.published.config;
-        let comp = serde_dhall::from_str(&full)
-            .parse::<routine::Routine>()
-            .map_err(|e| format!("{}", e))?;
-        let fs = comp.to_full_workout()?;
-        Ok(fs)
-    }
-    fn elapsed_millis(&self) -> i64 {
-        match self.published.state {
-            RunningState::RunningSince(start) if self.last_update > start => {
-                self.last_update - start
+    fn compile_config(&mut self) {
+        self.routine_hash = routine::config_hash(&self.config);
+        self.toggled.clear();
+        match routine::compile(&self.config) {
+            Ok(comp) => {
+                self.routine = comp.to_full_workout();
+                self.tree = comp.to_full_tree().ok();
+                self.routine_sounds = comp.sounds().cloned();
             }
-            RunningState::RunningSince(_) => 0, //Start is in the future. Sad times.
-            RunningState::PausedAfter(p) => p,
-            RunningState::Config => 0,
+            Err(e) => {
+                self.routine = Err(e);
+                self.tree = None;
+                self.routine_sounds = None;
+            }
+        }
+        self.resolve_sounds();
+    }
+    fn resolve_sounds(&mut self) {
+        self.sounds = self.prefs.resolve(self.routine_sounds.as_ref());
+        self.engine.warning = i64::from(self.sounds.warning) * 1000;
+    }
+    fn speech_changed(&mut self, context: &crate::Context) {
+        if let Err(e) = LocalStorage::insert(SPEECH_KEY, &self.speech) {
+            error!("Failed to save speech settings", e);
+        }
+        self.voices = context
+            .voices(&self.speech.lang)
+            .iter()
+            .map(|v| v.name())
+            .collect();
+        self.engine.halfway = self.speech.enabled && !self.speech.templates.halfway.is_empty();
+    }
+    fn prefs_changed(&mut self, context: &crate::Context) {
+        if let Err(e) = LocalStorage::insert(PREFS_KEY, &self.prefs) {
+            error!("Failed to save sound settings", e);
+        }
+        self.resolve_sounds();
+        self.set_volume(context);
+    }
+    /// Muting goes through the volume too, to silence anything queued.
+    fn set_volume(&self, context: &crate::Context) {
+        context.set_volume(if self.sounds.muted {
+            0.
+        } else {
+            self.prefs.volume
+        });
+    }
+    pub fn published(&self) -> PublishedModel {
+        PublishedModel {
+            timer: self.timer.clone(),
+            config: self.config.clone(),
+            queue: self.queue.clone(),
+            updated: self.updated,
+            circuit: self.circuit.clone(),
+            roster: self.roster.clone(),
+            from: self.me.clone(),
+            session: self.engine.session.clone(),
+        }
+    }
+    /// Of what the session's indexes and times depend on: each item's name,
+    /// length and kind.
+    fn compiled_hash(&self) -> String {
+        let canonical: Vec<String> = items(&self.routine)
+            .iter()
+            .map(|i| {
+                let secs = i.duration.map_or(String::new(), |d| d.to_string());
+                format!("{}\t{}\t{}", i.name, secs, i.kind().name())
+            })
+            .collect();
+        routine::config_hash(&canonical.join("\n"))
+    }
+    fn save(&self) {
+        let saved = SavedSession {
+            published: self.published(),
+            compiled_hash: self.compiled_hash(),
+        };
+        if let Err(e) = LocalStorage::insert(STORAGE_KEY, &saved) {
+            error!("Failed to save session", e);
+        }
+    }
+    /// Pick up from where we were before a reload. The session is dropped
+    /// if the routine no longer compiles to the same thing.
+    pub fn restore(&mut self, saved: SavedSession) {
+        self.timer = saved.published.timer;
+        self.load_config(saved.published.config);
+        self.queue = saved.published.queue;
+        self.circuit = saved.published.circuit;
+        self.roster = saved.published.roster;
+        if self.compiled_hash() == saved.compiled_hash {
+            self.updated = saved.published.updated;
+            self.engine.apply_remote(saved.published.session);
+        }
+    }
+    /// We've (re)joined the room. Say hello, and tell everyone where we're
+    /// up to: anyone further on will answer with their newer state.
+    pub fn rejoined(&mut self, context: &crate::Context) {
+        self.greet(context);
+        if self.updated > 0 && self.may_control() {
+            context.send(&RoomMsg::Update(self.published()));
         }
     }
+    /// Say hello, and forget anyone who's stopped saying it.
+    fn greet(&mut self, context: &crate::Context) {
+        let now = context.now();
+        self.greeted = now;
+        self.peers.retain(|_, &mut at| now - at < 3 * HELLO_MILLIS);
+        context.send(&RoomMsg::Hello {
+            from: self.me.clone(),
+            timer: self.timer.clone(),
+        });
+    }
+    /// Heard from `from`, on `timer`. Only those on our timer are taking
+    /// part in our session.
+    fn heard(&mut self, from: &str, timer: &str, now: i64) {
+        if from.is_empty() || from == self.me {
+            return;
+        }
+        self.peers.insert(from.to_owned(), now);
+        if timer == self.timer {
+            self.recorder.saw(from.to_owned());
+        }
+    }
+    /// Replaces the routine locally, e.g. from a share link, and shows it on
+    /// the config page.
+    pub fn load_config(&mut self, config: String) {
+        self.config = config;
+        self.engine.session.state = RunningState::Config;
+        self.compile_config();
+    }
+    /// Loads a share link's routine, and tells the room about it if it's
+    /// ours to change: otherwise the room's next update would quietly
+    /// replace it. If we're not in the room yet, `rejoined` tells them.
+    pub fn load_shared(&mut self, config: String, context: &crate::Context) {
+        self.load_config(config);
+        if self.may_control() {
+            self.run_effects(vec![Effect::Announce], context);
+        }
+    }
+    fn share_url(&self, with_room: bool) -> String {
+        let link = share::SharedLink {
+            config: self.config.clone(),
+            room: if with_room {
+                Some(self.room.clone())
+            } else {
+                None
+            },
+        };
+        let url = crate::Urls::new(&self.base_url)
+            .config()
+            .set_hash(link.to_fragment());
+        format!(
+            "{}{}",
+            window().location().origin().unwrap_or_default(),
+            url
+        )
+    }
     pub fn get_routine_item(&self, ix: usize) -> &FlatStatus {
-        self.routine
-            .as_ref()
-            .ok()
-            .and_then(|z| z.get(ix))
-            .unwrap_or(&END_STATUS)
+        self.engine.get_item(items(&self.routine), ix)
     }
     pub fn current_routine_item(&self) -> Option<&FlatStatus> {
-        match self.published.state {
-            RunningState::Config => None,
-            _ => Some(self.get_routine_item(self.published.routine_ix)),
-        }
-    }
-    pub fn goto_item(&mut self, new_ix: usize, context: &crate::Context) {
-        self.published.routine_ix = new_ix;
-        match self.published.state {
-            RunningState::RunningSince(_) => {
-                self.published.state =
-                    RunningState::RunningSince(context.current_time().timestamp_millis());
-                let item = self
-                    .current_routine_item()
-                    .expect("Valid workout item when running");
-                let freq = if item.is_rest() { 440. } else { 880. };
-                context.beep(0.2, freq);
-            }
-            RunningState::PausedAfter(_) => {
-                self.published.state = RunningState::PausedAfter(0);
-            }
-            RunningState::Config => {}
-        }
-        context.announce(&self.published);
-    }
-    pub fn time_fn(&mut self, context: &crate::Context) -> Vec<crate::subs::Event> {
-        let old_elapsed = self.elapsed_millis();
-        self.last_update = context.current_time().timestamp_millis();
-        if let Some(d) = self.current_routine_item().and_then(|x| x.duration) {
-            let elapsed = self.elapsed_millis();
-            let remaining_millis = d as i64 * 1000 - elapsed;
-            if remaining_millis <= 0 {
-                self.goto_item(self.published.routine_ix + 1, context)
-            } else {
-                let remaining_now = d as i64 * 1000 - elapsed;
-                if remaining_now < 3000 {
-                    let whole_rem_now = remaining_now / 1000;
-                    let whole_rem_before = (d as i64 * 1000 - old_elapsed) / 1000;
-                    if whole_rem_before != whole_rem_now {
-                        context.beep(0.1, 440.);
+        self.engine.current_item(items(&self.routine))
+    }
+    /// Carry out what the engine asked for.
+    fn run_effects(&mut self, effects: Vec<Effect>, context: &crate::Context) {
+        for e in effects {
+            match e {
+                Effect::Transition(_) => {}
+                Effect::Cue(c) => {
+                    let ix = match c {
+                        Cue::Enter(ix) | Cue::Item(ix, _) | Cue::Beat(ix, _) => ix,
+                        _ => self.engine.session.routine_ix,
+                    };
+                    let item = self.get_routine_item(ix);
+                    let sound = self.sounds.sound(c, item);
+                    // Queued ahead of time, and so already heard.
+                    let heard = context.queued(c);
+                    let spoken =
+                        self.speech
+                            .phrase(c, items(&self.routine), self.engine.session.routine_ix);
+                    match spoken {
+                        Some(u) if u.replaces_tones => {
+                            context.say(&u, &self.speech, 0);
+                        }
+                        Some(u) => {
+                            if !heard {
+                                context.play(&sound);
+                            }
+                            context.say(&u, &self.speech, context.sound_millis(&sound));
+                        }
+                        None if !heard => context.play(&sound),
+                        None => {}
                     }
+                    if let Some(cue) = match c {
+                        Cue::Item(_, n) => item.cues.get(n),
+                        _ => None,
+                    } {
+                        self.flash = Some((cue.label.clone(), context.now() + FLASH_MILLIS));
+                    }
+                }
+                // Displays follow the room's schedule without a word: the
+                // room would only turn them away.
+                Effect::Announce if !self.may_control() => self.save(),
+                Effect::Announce => {
+                    self.updated = context.now();
+                    context.send(&RoomMsg::Update(self.published()));
+                    self.save();
                 }
             }
         }
-        Vec::new()
+    }
+    pub fn time_fn(&mut self, context: &crate::Context) {
+        if self.flash.as_ref().map_or(false, |f| f.1 < context.now()) {
+            self.flash = None;
+        }
+        if self.greeted > 0 && context.now() - self.greeted >= HELLO_MILLIS {
+            self.greet(context);
+        }
+        let effects = self.engine.tick(items(&self.routine), context);
+        // Cues alone don't change what's coming up; moving on does.
+        let moved = effects.iter().any(|e| !matches!(e, Effect::Cue(_)));
+        self.run_effects(effects, context);
+        if moved || context.now() - self.planned_at >= REPLAN_MILLIS {
+            self.schedule(context);
+        }
+        self.record(context);
+    }
+    /// Queue up the cues coming up on the audio clock, so they go off on
+    /// time even if we're slow to tick. Anything spoken instead of played
+    /// is left to `run_effects`.
+    fn schedule(&mut self, context: &crate::Context) {
+        self.planned_at = context.now();
+        let items = items(&self.routine);
+        let ix = self.engine.session.routine_ix;
+        let plan = self
+            .engine
+            .upcoming(items, scheduler::HORIZON)
+            .into_iter()
+            .filter(|&(_, cue)| {
+                !self
+                    .speech
+                    .phrase(cue, items, ix)
+                    .map_or(false, |u| u.replaces_tones)
+            })
+            .map(|(at, cue)| {
+                let item = match cue {
+                    Cue::Enter(i) | Cue::Item(i, _) | Cue::Beat(i, _) => self.get_routine_item(i),
+                    _ => self.get_routine_item(ix),
+                };
+                (Planned { at, cue }, self.sounds.sound(cue, item))
+            })
+            .collect();
+        context.schedule(plan);
+    }
+    /// Keep track of the session for the history page, storing it once it's
+    /// over.
+    fn record(&mut self, context: &crate::Context) {
+        let name = self.tree.as_ref().map_or("", |t| t.name());
+        if let Some(r) = self.recorder.observe(
+            &self.engine.session,
+            items(&self.routine),
+            name,
+            &self.routine_hash,
+            context.now(),
+        ) {
+            save_record(r);
+        }
+        self.keep_recording(context.now());
+    }
+    /// Leaving the room: whatever was running is over as far as our
+    /// history goes.
+    fn stop_recording(&mut self, now: i64) {
+        let others = self.recorders.values_mut();
+        for r in others
+            .chain(Some(&mut self.recorder))
+            .filter_map(|r| r.finish(now))
+        {
+            save_record(r);
+        }
+        self.keep_recording(now);
+    }
+    fn keep_recording(&mut self, now: i64) {
+        if self.recorder.unkept(now) {
+            self.store_recording();
+        }
+    }
+    fn store_recording(&self) {
+        let kept = match self.recorder.recording() {
+            Some(r) => LocalStorage::insert(RECORDING_KEY, r),
+            None => LocalStorage::remove(RECORDING_KEY),
+        };
+        if let Err(e) = kept {
+            error!("Failed to save recording", e);
+        }
+    }
+    /// Switch to another of the room's timers, picking it up from where we
+    /// last heard it was. One we've not heard of starts on our routine, and
+    /// the room is told of it. The one we leave carries on being recorded
+    /// where it was, rather than counting as abandoned.
+    fn follow(&mut self, timer: String) -> Vec<Effect> {
+        if timer == self.timer {
+            return Vec::new();
+        }
+        let left = self.published();
+        let recorder = self
+            .recorders
+            .remove(&timer)
+            .unwrap_or_else(|| history::Recorder::new(Some(self.me.clone())));
+        let left_recorder = std::mem::replace(&mut self.recorder, recorder);
+        self.recorders.insert(left.timer.clone(), left_recorder);
+        self.store_recording();
+        self.others.insert(left.timer.clone(), left);
+        self.timer = timer;
+        let effects = match self.others.remove(&self.timer) {
+            Some(p) => {
+                if self.config != p.config {
+                    self.config = p.config;
+                    self.compile_config();
+                }
+                self.queue = p.queue;
+                self.updated = p.updated;
+                self.circuit = p.circuit;
+                self.roster = p.roster;
+                self.engine.apply_remote(p.session);
+                Vec::new()
+            }
+            None => {
+                self.queue.clear();
+                self.updated = 0;
+                self.circuit = Circuit::default();
+                self.roster = Roster::default();
+                self.engine.session = engine::Session::init();
+                vec![Effect::Announce]
+            }
+        };
+        self.save();
+        effects
+    }
+    fn header(&self) -> timers::Header<'_> {
+        timers::Header {
+            timer: &self.timer,
+            updated: self.updated,
+            from: &self.me,
+            roster: &self.roster,
+        }
+    }
+    /// Take on an update for the timer we're following.
+    fn apply(&mut self, p: PublishedModel) -> Vec<Effect> {
+        if self.config != p.config {
+            self.config = p.config;
+            self.compile_config();
+        }
+        if self.queue != p.queue {
+            self.queue_error = None;
+            self.queue = p.queue;
+        }
+        self.updated = p.updated;
+        self.roster = p.roster;
+        if self.circuit != p.circuit {
+            let stations = p.circuit.stations.len();
+            self.station = self.station.filter(|&s| s < stations);
+            self.circuit = p.circuit;
+        }
+        let effects = self.engine.apply_remote(p.session);
+        self.save();
+        effects
+    }
+    fn may_control(&self) -> bool {
+        self.roster.may_control(&self.me)
     }
 }
 pub fn update(
@@ -138,108 +664,922 @@ pub fn update(
     orders: &mut impl Orders<Msg>,
     context: &crate::Context,
 ) {
-    match msg {
-        Msg::Go => {
-            context.beep(0.1, 880.);
-            match model.published.state {
-                RunningState::RunningSince(start) => {
-                    let done = context.current_time() - Duration::milliseconds(start);
-                    model.published.state = RunningState::PausedAfter(done.timestamp_millis());
-                }
-                RunningState::PausedAfter(done) => {
-                    let new_start = context.current_time() - Duration::milliseconds(done);
-                    model.published.state =
-                        RunningState::RunningSince(new_start.timestamp_millis());
-                }
-                RunningState::Config => {
-                    model.published.routine_ix = 0;
-                    model.published.state =
-                        RunningState::RunningSince(context.current_time().timestamp_millis());
+    if msg.is_control() && !model.may_control() {
+        return;
+    }
+    let effects = match msg {
+        Msg::Go => model.engine.go(context),
+        Msg::ChangeItem(new_ix) => model
+            .engine
+            .goto_item(items(&model.routine), new_ix, context),
+        Msg::Adjust(secs) => model
+            .engine
+            .adjust(items(&model.routine), secs * 1000, context),
+        Msg::Undo => model.engine.undo(context),
+        Msg::StartIn(secs) => model
+            .engine
+            .start_at(context.current_time().timestamp_millis() + secs * 1000),
+        Msg::StartAt(hhmm) => {
+            let now = context.current_time().timestamp_millis();
+            let offset = js_sys::Date::new_0().get_timezone_offset() as i64;
+            match crate::workout::next_local_time(now, &hhmm, offset) {
+                Some(at) => model.engine.start_at(at),
+                None => Vec::new(),
+            }
+        }
+        Msg::ToConfig => model.engine.to_config(),
+        Msg::ConfigChanged(c) => {
+            model.config = c;
+            model.compile_config();
+            vec![Effect::Announce]
+        }
+        Msg::QueueConfig => {
+            model.queue.push(model.config.clone());
+            vec![Effect::Announce]
+        }
+        Msg::ClearQueue => {
+            model.queue.clear();
+            model.queue_error = None;
+            vec![Effect::Announce]
+        }
+        // A routine that doesn't compile stays at the front of the queue,
+        // rather than leaving the room with nothing to run.
+        Msg::StartNext => match model.queue.first().map(String::as_str).map(check_config) {
+            None => Vec::new(),
+            Some(Err(e)) => {
+                model.queue_error = Some(e);
+                Vec::new()
+            }
+            Some(Ok(())) => {
+                model.queue_error = None;
+                let next = model.queue.remove(0);
+                model.load_config(next);
+                model.engine.go(context)
+            }
+        },
+        Msg::ToggleMute => {
+            model.prefs.muted = !model.prefs.muted;
+            model.prefs_changed(context);
+            Vec::new()
+        }
+        Msg::SetVolume(v) => {
+            if let Ok(v) = v.parse::<f32>() {
+                model.prefs.volume = (v / 100.).max(0.).min(1.);
+                model.prefs_changed(context);
+            }
+            Vec::new()
+        }
+        Msg::ToggleOwnSounds => {
+            model.prefs.profile = match model.prefs.profile {
+                Some(_) => None,
+                None => Some(model.routine_sounds.clone().unwrap_or_default()),
+            };
+            model.prefs_changed(context);
+            Vec::new()
+        }
+        Msg::SetWarning(w) => {
+            if let (Some(p), Ok(w)) = (&mut model.prefs.profile, w.parse()) {
+                p.warning = w;
+                model.prefs_changed(context);
+            }
+            Vec::new()
+        }
+        Msg::SetPattern(slot, s) => {
+            match (&mut model.prefs.profile, sound::parse_pattern(&s)) {
+                (Some(p), Ok(tones)) => {
+                    *p.slot_mut(slot) = tones;
+                    model.prefs_changed(context);
                 }
+                (_, Err(e)) => error!(e),
+                (None, _) => {}
+            }
+            Vec::new()
+        }
+        Msg::SetSample(slot, name) => {
+            if let Some(p) = &mut model.prefs.profile {
+                p.set_sample(slot, Some(name).filter(|n| !n.is_empty()));
+                model.prefs_changed(context);
             }
-            context.announce(&model.published);
+            Vec::new()
         }
-        Msg::ChangeItem(new_ix) => model.goto_item(new_ix, context),
-        Msg::ToConfig => {
-            model.published.state = RunningState::Config;
-            context.announce(&model.published);
+        Msg::AddSample(file) => {
+            let name = file.name();
+            let name = match name.rfind('.') {
+                Some(dot) if dot > 0 => name[..dot].to_owned(),
+                _ => name,
+            };
+            orders.perform_cmd(async move {
+                wasm_bindgen_futures::JsFuture::from(file.array_buffer())
+                    .await
+                    .ok()
+                    .map(|b| Msg::SampleRead(name, js_sys::Uint8Array::new(&b).to_vec()))
+            });
+            Vec::new()
         }
-        Msg::ConfigChanged(c) => {
-            model.published.config = c;
-            model.routine = model.compile_config();
-            context.announce(&model.published);
+        Msg::SampleRead(name, bytes) => {
+            let mut stored = stored_samples();
+            stored.insert(name.clone(), base64::encode(&bytes));
+            store_samples(&stored);
+            context.load_sample(name.clone(), &bytes);
+            if !model.samples.contains(&name) {
+                model.samples.push(name);
+            }
+            Vec::new()
+        }
+        Msg::RemoveSample(name) => {
+            let mut stored = stored_samples();
+            stored.remove(&name);
+            store_samples(&stored);
+            context.unload_sample(&name);
+            model.samples.retain(|s| *s != name);
+            Vec::new()
+        }
+        Msg::ToggleSpeech => {
+            model.speech.enabled = !model.speech.enabled;
+            model.speech_changed(context);
+            Vec::new()
+        }
+        Msg::SetLang(lang) => {
+            model.speech.lang = lang;
+            model.speech.voice = None;
+            model.speech_changed(context);
+            Vec::new()
+        }
+        Msg::SetVoice(voice) => {
+            model.speech.voice = Some(voice).filter(|v| !v.is_empty());
+            model.speech_changed(context);
+            Vec::new()
+        }
+        Msg::SetTemplate(slot, text) => {
+            *model.speech.templates.slot_mut(slot) = text;
+            model.speech_changed(context);
+            Vec::new()
+        }
+        Msg::ToggleBlock(depth, start) => {
+            if !model.toggled.remove(&(depth, start)) {
+                model.toggled.insert((depth, start));
+            }
+            Vec::new()
+        }
+        Msg::ToggleKeys => {
+            model.show_keys = !model.show_keys;
+            Vec::new()
+        }
+        Msg::ShowHistory => {
+            orders.notify(crate::subs::Event::ShowHistory);
+            Vec::new()
         }
         Msg::Disconnect => {
+            model.stop_recording(context.now());
             orders.notify(crate::subs::Event::Disconnect);
+            Vec::new()
         }
-        Msg::ExternalUpdate(p) => {
-            if model.published.config == p.config {
-                model.published = p;
-            } else {
-                model.published = p;
-                model.routine = model.compile_config();
+        Msg::SetStations(s) => {
+            model.circuit.stations = circuit::parse_list(&s);
+            model.station = model.station.filter(|&s| s < model.circuit.stations.len());
+            vec![Effect::Announce]
+        }
+        Msg::SetGroups(g) => {
+            model.circuit.groups = circuit::parse_list(&g);
+            vec![Effect::Announce]
+        }
+        Msg::ShowStation(s) => {
+            model.station = s.parse().ok();
+            Vec::new()
+        }
+        Msg::SetRole(device, role) => match roles::Role::from_name(&role) {
+            Some(r) if model.roster.set_role(&model.me, &device, r) => vec![Effect::Announce],
+            _ => Vec::new(),
+        },
+        Msg::FollowTimer(t) => model.follow(t.trim().to_owned()),
+        Msg::ClaimRoom if model.roster.owner().is_none() => {
+            model.roster.claim(&model.me, context.now());
+            vec![Effect::Announce]
+        }
+        Msg::ClaimRoom => Vec::new(),
+        Msg::FromRoom(RoomMsg::Hello { from, timer }) => {
+            model.heard(&from, &timer, context.now());
+            Vec::new()
+        }
+        Msg::FromRoom(RoomMsg::Update(p)) => {
+            model.heard(&p.from, &p.timer, context.now());
+            let known = model.others.get(&p.timer).map(PublishedModel::header);
+            match timers::route(p.header(), model.header(), known) {
+                // No answer to a refusal, as they'd only turn ours away in
+                // turn: they'll come round with the next update from the
+                // room.
+                Route::Refuse | Route::Ignore => Vec::new(),
+                Route::Keep => {
+                    model.others.insert(p.timer.clone(), p);
+                    Vec::new()
+                }
+                Route::Behind => {
+                    if model.may_control() {
+                        context.send(&RoomMsg::Update(model.published()));
+                    }
+                    Vec::new()
+                }
+                Route::Apply => model.apply(p),
             }
         }
-    }
+    };
+    model.run_effects(effects, context);
+    model.schedule(context);
+    model.record(context);
 }
 
 fn view_item(class: &str, item: &FlatStatus, ix: usize) -> Node<Msg> {
     div![
-        class! {"item", class, if item.is_rest() {"rest"} else {"work"}},
+        C!["item", class, item.classes()],
         div![class! {"reps"}, item.rep_str()],
         div![class! {"duration"}, item.dur_str()],
+        item.cadence
+            .map(|c| div![class! {"cadence"}, format!("{} bpm", c.bpm)]),
         &item.name,
         ev(Ev::Click, move |_| Msg::ChangeItem(ix))
     ]
 }
 fn view_list_item(ix: usize, item: &FlatStatus, active_ix: usize) -> Node<Msg> {
     li![
-        class! {if item.is_rest() {"rest"} else {"work"}
-        if active_ix > ix { "done" } else if active_ix == ix {"active"} else {"future"}},
+        C![
+            item.classes(),
+            if active_ix > ix {
+                "done"
+            } else if active_ix == ix {
+                "active"
+            } else {
+                "future"
+            }
+        ],
         ev(Ev::Click, move |_| Msg::ChangeItem(ix)),
         span![class! {"desc"}, format!("{} {}", item.rep_str(), item.name)],
         span![class! {"time"}, item.dur_str()]
     ]
 }
+/// The routine as it's written, with each block collapsible and counting
+/// its rounds. `start` is where `node` starts in the flat list.
+fn view_tree(
+    model: &Model,
+    items: &[FlatStatus],
+    node: &routine::RoutineNode,
+    start: usize,
+    depth: usize,
+) -> Node<Msg> {
+    let current = model.engine.session.routine_ix;
+    let (name, repeats) = match node {
+        routine::RoutineNode::Work { .. } => {
+            return items
+                .get(start)
+                .map_or(empty![], |item| view_list_item(start, item, current))
+        }
+        routine::RoutineNode::Block { name, repeats, .. } => (name, *repeats),
+    };
+    let end = start + node.flat_len();
+    let here = (start..end).contains(&current);
+    let open = here != model.toggled.contains(&(depth, start));
+    let rounds = node.laid_out(start);
+    let counter = match rounds.iter().rposition(|&(_, at)| at <= current) {
+        _ if repeats < 2 => String::new(),
+        Some(r) if here => format!("{}/{}", r + 1, repeats),
+        _ => format!("x{}", repeats),
+    };
+    li![
+        class! {"block"},
+        div![
+            C![
+                "header",
+                if end <= current {
+                    "done"
+                } else if here {
+                    "active"
+                } else {
+                    "future"
+                }
+            ],
+            button![
+                if open { "-" } else { "+" },
+                ev(Ev::Click, move |e| {
+                    e.stop_propagation();
+                    Msg::ToggleBlock(depth, start)
+                })
+            ],
+            span![class! {"desc"}, name],
+            span![class! {"time"}, counter],
+            ev(Ev::Click, move |_| Msg::ChangeItem(start))
+        ],
+        IF!(open => ul![rounds
+            .into_iter()
+            .map(|(child, at)| view_tree(model, items, child, at, depth + 1))])
+    ]
+}
+
+const KEYS: &[(&str, &str)] = &[
+    ("Space", "Pause / resume"),
+    ("← →", "Previous / next item"),
+    ("↑ ↓", "10s more / less"),
+    ("U", "Undo"),
+    ("Esc", "Back to config"),
+    ("Ctrl+Enter", "Start (from the editor)"),
+    ("?", "Show / hide this list"),
+];
+
+/// What a key press does, if anything. Keys typed into the editor are left
+/// alone, apart from Ctrl+Enter to start.
+pub fn key_msg(model: &Model, key: &str, ctrl: bool, typing: bool) -> Option<Msg> {
+    let ix = model.engine.session.routine_ix;
+    let last = items(&model.routine).len().saturating_sub(1);
+    let active = matches!(
+        model.engine.session.state,
+        RunningState::RunningSince(_) | RunningState::PausedAfter(_)
+    );
+    match key {
+        "Enter" if ctrl && model.engine.session.state == RunningState::Config => {
+            model.routine.as_ref().ok().map(|_| Msg::Go)
+        }
+        _ if typing => None,
+        "?" => Some(Msg::ToggleKeys),
+        "Escape" if model.show_keys => Some(Msg::ToggleKeys),
+        _ if model.engine.session.state == RunningState::Config => None,
+        " " => Some(Msg::Go),
+        "Escape" => Some(Msg::ToConfig),
+        "ArrowLeft" if active && ix > 0 => Some(Msg::ChangeItem(ix - 1)),
+        "ArrowRight" if active && ix < last => Some(Msg::ChangeItem(ix + 1)),
+        "ArrowUp" if active => Some(Msg::Adjust(10)),
+        "ArrowDown" if active => Some(Msg::Adjust(-10)),
+        "u" | "U" if active => Some(Msg::Undo),
+        _ => None,
+    }
+}
 
+fn view_keys() -> Node<Msg> {
+    div![
+        class! {"keys"},
+        ev(Ev::Click, |_| Msg::ToggleKeys),
+        table![KEYS
+            .iter()
+            .map(|(k, what)| tr![th![k.to_string()], td![what.to_string()]])]
+    ]
+}
 pub fn view(model: &Model) -> Node<Msg> {
-    if let RunningState::Config = model.published.state {
-        view_config(model)
+    let page = match model.engine.session.state {
+        RunningState::Config => view_config(model),
+        RunningState::Finished(_) => view_finished(model),
+        _ => view_running(model),
+    };
+    if model.show_keys {
+        div![page, view_keys()]
     } else {
-        view_running(model)
+        page
     }
 }
 fn view_config(model: &Model) -> Node<Msg> {
+    let control = model.may_control();
     div![
         class! {"config"},
-        p![class! {"help"}, "Workout thingy. Config below is written in Dhall. Errors or start button on the right. In the main workout view, click the time at the top to pause/resume. Click any other item to jump to that item in the sequence."],
-        textarea![&model.published.config, input_ev(Ev::Input, Msg::ConfigChanged)],
+        p![class! {"help"}, "Workout thingy. Config below is written in Dhall. Errors or start button on the right. In the main workout view, click the time at the top to pause/resume. Click any other item to jump to that item in the sequence. Press ? for keyboard shortcuts. ",
+            button!["History", ev(Ev::Click, |_| Msg::ShowHistory)]
+        ],
+        textarea![
+            attrs! {At::ReadOnly => (!control).as_at_value()},
+            &model.config,
+            input_ev(Ev::Input, Msg::ConfigChanged)
+        ],
         match &model.routine {
             Err(s) => pre![class! {"error"}, s],
-            Ok(_) => button!["Start", ev(Ev::Click, |_| Msg::Go)],
+            Ok(_) => div![
+                class! {"result"},
+                IF!(control => button!["Start", ev(Ev::Click, |_| Msg::Go)]),
+                IF!(control => div![
+                    class! {"scheduled-start"},
+                    button!["Start in 60s", ev(Ev::Click, |_| Msg::StartIn(60))],
+                    " or at ",
+                    input![
+                        attrs! {At::Type => "time"},
+                        input_ev(Ev::Change, Msg::StartAt)
+                    ]
+                ]),
+                IF!(control => view_queue(model)),
+                view_sound_prefs(model),
+                view_speech_prefs(model),
+                model.tree.as_ref().map(view_sheet_links),
+                view_circuit(model),
+                view_timers(model),
+                view_roles(model),
+                view_share_links(model)
+            ],
+        }
+    ]
+}
+fn view_queue(model: &Model) -> Node<Msg> {
+    div![
+        class! {"queue"},
+        button!["Add to queue", ev(Ev::Click, |_| Msg::QueueConfig)],
+        if model.queue.is_empty() {
+            empty![]
+        } else {
+            span![
+                format!(" {} queued to run after this one ", model.queue.len()),
+                button!["Clear", ev(Ev::Click, |_| Msg::ClearQueue)]
+            ]
+        }
+    ]
+}
+fn checkbox(label: &str, checked: bool, msg: Msg) -> Node<Msg> {
+    label![
+        input![
+            attrs! {At::Type => "checkbox", At::Checked => checked.as_at_value()},
+            ev(Ev::Click, move |_| msg)
+        ],
+        label
+    ]
+}
+fn view_sound_prefs(model: &Model) -> Node<Msg> {
+    div![
+        class! {"sound-prefs"},
+        checkbox("Mute this device", model.prefs.muted, Msg::ToggleMute),
+        " ",
+        label![
+            "Volume ",
+            input![
+                attrs! {At::Type => "range", At::Min => "0", At::Max => "100", At::Value => (model.prefs.volume * 100.).round()},
+                input_ev(Ev::Change, Msg::SetVolume)
+            ]
+        ],
+        " ",
+        checkbox(
+            "Use this device's own sounds",
+            model.prefs.profile.is_some(),
+            Msg::ToggleOwnSounds
+        ),
+        model.prefs.profile.as_ref().map(|p| {
+            div![
+                label![
+                    "Countdown seconds ",
+                    input![
+                        attrs! {At::Type => "number", At::Min => "0", At::Value => p.warning},
+                        input_ev(Ev::Change, Msg::SetWarning)
+                    ]
+                ],
+                p![format!(
+                    "End a tone with :{} to play it on an instrument.",
+                    synth::INSTRUMENTS[1..]
+                        .iter()
+                        .map(|i| i.1)
+                        .collect::<Vec<_>>()
+                        .join(", :")
+                )],
+                sound::SLOTS.iter().map(|&(slot, name)| {
+                    label![
+                        format!("{} (Hz x ms) ", name),
+                        input![
+                            attrs! {At::Value => sound::format_pattern(p.slot(slot))},
+                            input_ev(Ev::Change, move |s| Msg::SetPattern(slot, s))
+                        ],
+                        if model.samples.is_empty() {
+                            empty![]
+                        } else {
+                            view_sample_choice(model, p.sample(slot), slot)
+                        }
+                    ]
+                })
+            ]
+        }),
+        view_samples(model)
+    ]
+}
+fn view_sample_choice(model: &Model, chosen: Option<&str>, slot: sound::Slot) -> Node<Msg> {
+    select![
+        option![attrs! {At::Value => ""}, "Tones"],
+        model.samples.iter().map(|s| {
+            option![
+                attrs! {At::Value => s, At::Selected => (chosen == Some(s.as_str())).as_at_value()},
+                s
+            ]
+        }),
+        input_ev(Ev::Change, move |s| Msg::SetSample(slot, s))
+    ]
+}
+/// Audio files this device can play instead of tones, where a routine or
+/// its own sounds ask for them by name.
+fn view_samples(model: &Model) -> Node<Msg> {
+    div![
+        class! {"samples"},
+        "Samples: ",
+        model.samples.iter().map(|s| {
+            let name = s.clone();
+            span![
+                s,
+                button!["x", ev(Ev::Click, move |_| Msg::RemoveSample(name))],
+                " "
+            ]
+        }),
+        input![
+            attrs! {At::Type => "file", At::Accept => "audio/*"},
+            ev(Ev::Change, |e| {
+                e.target()
+                    .and_then(|t| t.dyn_into::<web_sys::HtmlInputElement>().ok())
+                    .and_then(|i| i.files())
+                    .and_then(|f| f.get(0))
+                    .map(Msg::AddSample)
+            })
+        ]
+    ]
+}
+fn view_speech_prefs(model: &Model) -> Node<Msg> {
+    let s = &model.speech;
+    div![
+        class! {"speech-prefs"},
+        checkbox("Speak", s.enabled, Msg::ToggleSpeech),
+        if s.enabled {
+            div![
+                label![
+                    "Language ",
+                    input![
+                        attrs! {At::Value => s.lang},
+                        input_ev(Ev::Change, Msg::SetLang)
+                    ]
+                ],
+                label![
+                    "Voice ",
+                    select![
+                        option![attrs! {At::Value => ""}, "Default"],
+                        model.voices.iter().map(|v| {
+                            option![
+                                attrs! {At::Value => v, At::Selected => (s.voice.as_ref() == Some(v)).as_at_value()},
+                                v
+                            ]
+                        }),
+                        input_ev(Ev::Change, Msg::SetVoice)
+                    ]
+                ],
+                speech::SLOTS.iter().map(|&(slot, name)| {
+                    label![
+                        format!("{} ", name),
+                        input![
+                            attrs! {At::Value => s.templates.slot(slot)},
+                            input_ev(Ev::Change, move |t| Msg::SetTemplate(slot, t))
+                        ]
+                    ]
+                })
+            ]
+        } else {
+            empty![]
+        }
+    ]
+}
+fn view_finished(model: &Model) -> Node<Msg> {
+    let summary = model.engine.session.summary(items(&model.routine));
+    let row = |label: &str, value: String| tr![th![label], td![value]];
+    div![
+        class! {"finished"},
+        h1!["Finished"],
+        summary.map(|s| {
+            table![
+                row("Planned", crate::workout::timer(s.planned / 1000)),
+                row("Actual", crate::workout::timer(s.actual / 1000)),
+                row("Pauses", s.pauses.to_string()),
+                row("Items skipped", s.skipped.to_string()),
+                row("Items adjusted", s.adjusted.to_string()),
+            ]
+        }),
+        IF!(model.may_control() => div![
+            class! {"finished-actions"},
+            button!["Repeat", ev(Ev::Click, |_| Msg::Go)],
+            button!["Back to config", ev(Ev::Click, |_| Msg::ToConfig)],
+            if model.queue.is_empty() {
+                empty![]
+            } else {
+                button![
+                    format!("Start next routine ({} queued)", model.queue.len()),
+                    ev(Ev::Click, |_| Msg::StartNext)
+                ]
+            }
+        ]),
+        model
+            .queue_error
+            .as_ref()
+            .map(|e| pre![class! {"error"}, "Next routine: ", e])
+    ]
+}
+fn view_sheet_links(tree: &routine::RoutineNode) -> Node<Msg> {
+    div![
+        class! {"sheet-links"},
+        "Printable sheet: ",
+        a![
+            attrs! {At::Href => data_url("text/html", &sheet::to_html(tree)), At::Download => "routine.html"},
+            "HTML"
+        ],
+        " ",
+        a![
+            attrs! {At::Href => data_url("text/markdown", &sheet::to_markdown(tree)), At::Download => "routine.md"},
+            "Markdown"
+        ]
+    ]
+}
+/// Setting up the stations and groups of a circuit, and where this device
+/// stands.
+fn view_circuit(model: &Model) -> Node<Msg> {
+    let c = &model.circuit;
+    let locked = attrs! {At::Disabled => (!model.may_control()).as_at_value()};
+    div![
+        class! {"circuit"},
+        label![
+            "Circuit stations: ",
+            input![
+                attrs! {At::Type => "text", At::Value => c.stations.join(", "), At::Placeholder => "Squats, Press ups, ..."},
+                &locked,
+                input_ev(Ev::Change, Msg::SetStations)
+            ]
+        ],
+        label![
+            "Groups: ",
+            input![
+                attrs! {At::Type => "text", At::Value => c.groups.join(", "), At::Placeholder => "A, B, ..."},
+                &locked,
+                input_ev(Ev::Change, Msg::SetGroups)
+            ]
+        ],
+        IF!(!c.is_empty() => label![
+            "This device shows: ",
+            select![
+                option![
+                    attrs! {At::Value => "", At::Selected => model.station.is_none().as_at_value()},
+                    "Everything"
+                ],
+                c.stations.iter().enumerate().map(|(ix, s)| option![
+                    attrs! {At::Value => ix, At::Selected => (model.station == Some(ix)).as_at_value()},
+                    format!("Station {}: {}", ix + 1, s)
+                ]),
+                input_ev(Ev::Change, Msg::ShowStation)
+            ]
+        ])
+    ]
+}
+/// A device id, shortened to the random end of it.
+fn short_id(id: &str) -> &str {
+    &id[id.len().saturating_sub(6)..]
+}
+/// This device's role, and for the owner, everyone else's to change.
+fn view_roles(model: &Model) -> Node<Msg> {
+    let role = model.roster.role(&model.me);
+    let mut devices: BTreeSet<&str> = model.peers.keys().map(String::as_str).collect();
+    devices.extend(model.roster.roles.keys().map(String::as_str));
+    devices.remove(model.me.as_str());
+    div![
+        class! {"roles"},
+        format!("This device is {} {}", short_id(&model.me), role.name()),
+        IF!(model.roster.owner().is_none() => button![
+            "Take charge of this timer",
+            ev(Ev::Click, |_| Msg::ClaimRoom)
+        ]),
+        IF!(role == roles::Role::Owner => ul![devices.into_iter().map(|d| {
+            let id = d.to_owned();
+            let current = model.roster.role(d);
+            li![
+                format!("{} ", short_id(d)),
+                select![
+                    roles::ROLES.iter().map(|&(r, name)| option![
+                        attrs! {At::Value => name, At::Selected => (r == current).as_at_value()},
+                        name
+                    ]),
+                    input_ev(Ev::Change, move |r| Msg::SetRole(id, r))
+                ]
+            ]
+        })])
+    ]
+}
+fn timer_name(timer: &str) -> &str {
+    if timer.is_empty() {
+        "main"
+    } else {
+        timer
+    }
+}
+/// Which of the room's timers this device follows.
+fn view_timers(model: &Model) -> Node<Msg> {
+    let mut timers: BTreeSet<&str> = model.others.keys().map(String::as_str).collect();
+    timers.insert(&model.timer);
+    div![
+        class! {"timers"},
+        "Timer: ",
+        select![
+            timers.into_iter().map(|t| option![
+                attrs! {At::Value => t, At::Selected => (t == model.timer).as_at_value()},
+                timer_name(t)
+            ]),
+            input_ev(Ev::Change, Msg::FollowTimer)
+        ],
+        " or new: ",
+        input![
+            attrs! {At::Type => "text", At::Placeholder => "name"},
+            input_ev(Ev::Change, Msg::FollowTimer)
+        ]
+    ]
+}
+fn view_share_links(model: &Model) -> Node<Msg> {
+    div![
+        class! {"share-links"},
+        "Share: ",
+        a![attrs! {At::Href => model.share_url(false)}, "routine"],
+        " ",
+        a![
+            attrs! {At::Href => model.share_url(true)},
+            format!("routine and room {}", model.room)
+        ]
+    ]
+}
+fn control_button(label: &str, msg: Msg) -> Node<Msg> {
+    button![
+        label,
+        ev(Ev::Click, move |e| {
+            e.stop_propagation();
+            msg
+        })
+    ]
+}
+fn view_controls(model: &Model) -> Node<Msg> {
+    let session = &model.engine.session;
+    let adjusted = session
+        .adjustments
+        .get(&session.routine_ix)
+        .copied()
+        .unwrap_or(0);
+    if !model.may_control() {
+        return div![
+            class! {"controls"},
+            control_button(
+                if model.prefs.muted { "Unmute" } else { "Mute" },
+                Msg::ToggleMute
+            )
+        ];
+    }
+    div![
+        class! {"controls"},
+        if session.history.is_empty() {
+            empty![]
+        } else {
+            control_button("Undo", Msg::Undo)
+        },
+        control_button(
+            if model.prefs.muted { "Unmute" } else { "Mute" },
+            Msg::ToggleMute
+        ),
+        control_button("-10s", Msg::Adjust(-10)),
+        control_button("+10s", Msg::Adjust(10)),
+        if adjusted != 0 {
+            span![format!(
+                "{}{}",
+                if adjusted > 0 { "+" } else { "" },
+                crate::workout::timer(adjusted / 1000)
+            )]
+        } else {
+            empty![]
         }
     ]
 }
+/// A track round the time, filled in as the current item goes by.
+fn view_ring(fraction: Option<f64>) -> Vec<Node<Msg>> {
+    let ring = |class: &str, fraction: f64| {
+        rect![attrs! {
+            At::Class => class,
+            At::X => "0.5", At::Y => "0.5", At::Width => "42", At::Height => "17", At::Rx => "8.5",
+            At::PathLength => "100",
+            At::StrokeDashArray => format!("{:.2} 100", fraction * 100.)
+        }]
+    };
+    match fraction {
+        Some(f) => vec![ring("ring-track", 1.), ring("ring", f)],
+        None => Vec::new(),
+    }
+}
+/// The whole session, each item in proportion to its time.
+fn view_session_bar(model: &Model, items: &[FlatStatus], progress: engine::Progress) -> Node<Msg> {
+    let current = model.engine.session.routine_ix;
+    let percent = progress.done as f64 * 100. / progress.total.max(1) as f64;
+    div![
+        class! {"session"},
+        div![
+            class! {"bar"},
+            items.iter().enumerate().map(|(ix, item)| {
+                let d = model.engine.session.item_duration(items, ix).unwrap_or(0);
+                div![
+                    C![item.classes(), IF!(ix < current => "done")],
+                    style! {St::FlexGrow => d.to_string()},
+                    attrs! {At::Title => item.name}
+                ]
+            }),
+            div![
+                class! {"position"},
+                style! {St::Left => format!("{:.2}%", percent)}
+            ]
+        ],
+        div![
+            class! {"totals"},
+            IF!(!model.others.is_empty() => format!("{} timer: ", timer_name(&model.timer))),
+            format!(
+                "{} done, {} to go",
+                crate::workout::timer(progress.done / 1000),
+                crate::workout::timer((progress.total - progress.done + 999) / 1000)
+            )
+        ]
+    ]
+}
+/// Who's at each station in each round, the current round picked out.
+fn view_rotation(model: &Model, items: &[FlatStatus]) -> Node<Msg> {
+    let c = &model.circuit;
+    let now = circuit::round(items, model.engine.session.routine_ix);
+    div![
+        class! {"next", "rotation"},
+        table![
+            tr![th![], c.stations.iter().map(|s| th![s])],
+            c.matrix(circuit::rounds(items))
+                .into_iter()
+                .enumerate()
+                .map(|(r, row)| tr![
+                    C![if r < now {
+                        "done"
+                    } else if r == now {
+                        "active"
+                    } else {
+                        "future"
+                    }],
+                    th![(r + 1).to_string()],
+                    row.into_iter().map(|g| td![g.unwrap_or("")])
+                ])
+        ]
+    ]
+}
+/// What the people at one station need: their exercise, who's on it now,
+/// and who's next.
+fn view_station(model: &Model, items: &[FlatStatus], station: usize) -> Node<Msg> {
+    let c = &model.circuit;
+    let current = model.engine.session.routine_ix;
+    let round = circuit::round(items, current);
+    let rest = model.get_routine_item(current).is_rest();
+    div![
+        C![
+            "item",
+            "curr",
+            "station-info",
+            if rest { "rest" } else { "work" }
+        ],
+        div![
+            class! {"exercise"},
+            c.stations.get(station).map(String::as_str).unwrap_or("")
+        ],
+        div![
+            class! {"group"},
+            c.group_at(station, round).unwrap_or("Nobody")
+        ],
+        div![
+            class! {"next-group"},
+            format!(
+                "Then {}",
+                c.group_at(station, round + 1).unwrap_or("nobody")
+            )
+        ]
+    ]
+}
+fn view_broken(model: &Model, error: &str) -> Node<Msg> {
+    div![
+        class! {"workout"},
+        pre![class! {"error"}, error],
+        IF!(model.may_control() => button!["Back to config", ev(Ev::Click, |_| Msg::ToConfig)])
+    ]
+}
 fn view_running(model: &Model) -> Node<Msg> {
+    let items = match &model.routine {
+        Ok(items) => items,
+        // Someone in the room started a routine that doesn't compile here.
+        Err(e) => return view_broken(model, e),
+    };
     let current = model
         .current_routine_item()
         .expect("Valid routine item in view_running");
-    let next = model.get_routine_item(model.published.routine_ix + 1);
-    let time = match current.duration {
-        None => model.elapsed_millis() / 1000,
+    let next = model.get_routine_item(model.engine.session.routine_ix + 1);
+    let countdown = model.engine.countdown_millis();
+    let time = match (countdown, model.engine.remaining_millis(items)) {
         // see https://stackoverflow.com/a/17974
-        Some(d) => ((1000 * d as i64) - model.elapsed_millis() + 999) / 1000,
+        (Some(c), _) => (c + 999) / 1000,
+        (None, None) => model.engine.elapsed_millis(items) / 1000,
+        (None, Some(r)) => (r + 999) / 1000,
     };
-    let items = model.routine.as_ref().expect("good routine");
+    let progress = model.engine.progress(items);
+    let station = model.station.filter(|_| !model.circuit.is_empty());
     div![
         // --- Seconds ---
         div![
-            class! {"workout"},
+            C!["workout", IF!(station.is_some() => "station")],
             div![
-                class! {"time", if current.is_rest() {"rest"} else {"work"}},
+                C![
+                    "time",
+                    current.classes(),
+                    IF!(countdown.is_some() => "countdown")
+                ],
                 svg![
                     attrs![At::ViewBox=>"0 0 43 18"],
                     style![St::Width=>"100%"],
+                    view_ring(progress.item.filter(|_| countdown.is_none())),
                     text![
                         attrs![At::X=>"21", At::Y=>"14.5"],
                         style!["text-anchor"=>"middle"],
@@ -247,19 +1587,46 @@ fn view_running(model: &Model) -> Node<Msg> {
                     ]
                 ],
                 //workout::timer(time),
-                ev(Ev::Click, |_| Msg::Go)
+                model
+                    .flash
+                    .as_ref()
+                    .filter(|f| !f.0.is_empty())
+                    .map(|f| div![class! {"flash"}, &f.0]),
+                view_controls(model),
+                IF!(model.may_control() => ev(Ev::Click, |_| Msg::Go))
             ],
-            view_item("curr", current, model.published.routine_ix),
-            view_item("next", next, model.published.routine_ix + 1),
-            ul![
-                class! {"workout-list"},
-                items
-                    .iter()
-                    .enumerate()
-                    .filter(|(_, x)| !x.is_rest())
-                    .map(|(ix, i)| view_list_item(ix, i, model.published.routine_ix)),
-                li!["Back to Config", ev(Ev::Click, |_| Msg::ToConfig)]
-            ]
+            match station {
+                // A station display has no need of the rest.
+                Some(s) => vec![view_station(model, items, s)],
+                None => vec![
+                    view_session_bar(model, items, progress),
+                    view_item("curr", current, model.engine.session.routine_ix),
+                    if model.circuit.is_empty() {
+                        view_item("next", next, model.engine.session.routine_ix + 1)
+                    } else {
+                        view_rotation(model, items)
+                    },
+                    ul![
+                        class! {"workout-list"},
+                        match &model.tree {
+                            Some(tree) => tree
+                                .laid_out(0)
+                                .into_iter()
+                                .map(|(node, start)| view_tree(model, items, node, start, 1))
+                                .collect::<Vec<_>>(),
+                            None => items
+                                .iter()
+                                .enumerate()
+                                .filter(|(_, x)| !x.is_rest())
+                                .map(|(ix, i)| {
+                                    view_list_item(ix, i, model.engine.session.routine_ix)
+                                })
+                                .collect(),
+                        },
+                        IF!(model.may_control() => li!["Back to Config", ev(Ev::Click, |_| Msg::ToConfig)])
+                    ],
+                ],
+            }
         ],
     ]
 }
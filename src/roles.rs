@@ -0,0 +1,153 @@
+//! Who may do what in a room. The owner hands out roles, controllers run the
+//! timer, and displays only show it, so that a phone or the TV on the wall
+//! can't take over the session by accident.
+//!
+//! Nothing here is checked cryptographically. Devices say who they are, and
+//! every update names the owner, so a modified client can still pass itself
+//! off as the owner. This keeps honest devices in line; it won't stop
+//! someone set on hijacking the room.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Owner,
+    Controller,
+    Display,
+}
+
+pub const ROLES: [(Role, &str); 3] = [
+    (Role::Owner, "owner"),
+    (Role::Controller, "controller"),
+    (Role::Display, "display"),
+];
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        ROLES.iter().find(|r| r.0 == self).map_or("", |r| r.1)
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        ROLES.iter().find(|r| r.1 == name).map(|r| r.0)
+    }
+}
+
+/// Roles given out, by device id.
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct Roster {
+    pub roles: BTreeMap<String, Role>,
+    /// When the room was first taken, by the room's clock.
+    #[serde(default)]
+    pub claimed: i64,
+}
+
+impl Roster {
+    pub fn owner(&self) -> Option<&str> {
+        self.roles
+            .iter()
+            .find(|(_, &r)| r == Role::Owner)
+            .map(|(d, _)| d.as_str())
+    }
+    /// Until someone owns the room anyone can run it. After that, devices
+    /// which haven't been given a role only watch.
+    pub fn role(&self, device: &str) -> Role {
+        match self.owner() {
+            None => Role::Controller,
+            Some(_) => self.roles.get(device).copied().unwrap_or(Role::Display),
+        }
+    }
+    pub fn may_control(&self, device: &str) -> bool {
+        self.role(device) != Role::Display
+    }
+    /// Take the room at `at` if nobody owns it yet. Only ever asked for,
+    /// so that simply running the timer doesn't lock everyone else out.
+    pub fn claim(&mut self, device: &str, at: i64) {
+        if self.owner().is_none() && !device.is_empty() {
+            self.roles.insert(device.to_owned(), Role::Owner);
+            self.claimed = at;
+        }
+    }
+    /// Only the owner hands out roles. There's only ever one owner, so
+    /// handing that on steps down to controller.
+    pub fn set_role(&mut self, by: &str, device: &str, role: Role) -> bool {
+        if self.owner() != Some(by) || by == device {
+            return false;
+        }
+        if role == Role::Owner {
+            self.roles.insert(by.to_owned(), Role::Controller);
+        }
+        self.roles.insert(device.to_owned(), role);
+        true
+    }
+    /// Whether to take up an update from `sender`, bringing `incoming` as
+    /// the roles. Only the owner may change them, once there is one.
+    pub fn accepts(&self, sender: &str, incoming: &Roster) -> bool {
+        match self.owner() {
+            None => true,
+            Some(owner) if sender == owner => true,
+            Some(owner) => match incoming.owner() {
+                // Two devices took the room before hearing of each other.
+                // Everyone settles on the earlier claim, or failing that the
+                // lower id, and the other owner is left a display.
+                Some(theirs) if theirs != owner => {
+                    sender == theirs && (incoming.claimed, theirs) < (self.claimed, owner)
+                }
+                _ => self.may_control(sender) && incoming == self,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn open_until_owned() {
+        let mut r = Roster::default();
+        assert!(r.may_control("tv"));
+        assert!(r.accepts("tv", &Roster::default()));
+        r.claim("laptop", 1);
+        r.claim("tv", 2);
+        assert_eq!(r.owner(), Some("laptop"));
+        assert_eq!(r.role("tv"), Role::Display);
+        assert!(!r.accepts("tv", &r.clone()));
+        assert!(r.accepts("laptop", &r.clone()));
+    }
+    #[test]
+    pub fn only_owner_promotes() {
+        let mut r = Roster::default();
+        r.claim("laptop", 1);
+        let before = r.clone();
+        assert!(!r.set_role("phone", "phone", Role::Controller));
+        assert!(r.set_role("laptop", "phone", Role::Controller));
+        assert!(r.may_control("phone"));
+        // Someone else's list of roles is turned away.
+        assert!(!before.accepts("phone", &r));
+        assert!(before.accepts("laptop", &r));
+        let mut grabbed = r.clone();
+        grabbed.roles.insert("phone".into(), Role::Owner);
+        assert!(!r.accepts("phone", &grabbed));
+        // Handing over ownership.
+        assert!(r.set_role("laptop", "phone", Role::Owner));
+        assert_eq!(r.owner(), Some("phone"));
+        assert_eq!(r.role("laptop"), Role::Controller);
+        assert!(before.accepts("laptop", &r));
+    }
+    #[test]
+    pub fn settles_rival_claims() {
+        let claim = |device: &str, at| {
+            let mut r = Roster::default();
+            r.claim(device, at);
+            r
+        };
+        let (early, late) = (claim("b", 1), claim("a", 2));
+        assert!(late.accepts("b", &early));
+        assert!(!early.accepts("a", &late));
+        // At the same time, the lower id.
+        let (a, b) = (claim("a", 1), claim("b", 1));
+        assert!(b.accepts("a", &a));
+        assert!(!a.accepts("b", &b));
+        // Only the claimant can bring its claim.
+        assert!(!late.accepts("c", &early));
+    }
+}
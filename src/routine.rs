@@ -1,4 +1,5 @@
-use crate::workout::FlatStatus;
+use crate::sound::SoundProfile;
+use crate::workout::{Cadence, FlatStatus, ItemCue, Kind};
 use serde::Deserialize;
 use std::borrow::Cow;
 use std::collections::HashMap;
@@ -7,6 +8,12 @@ use std::collections::HashMap;
 struct SimpleWork {
     duration: u32,
     name: String,
+    #[serde(default)]
+    cues: Vec<ItemCue>,
+    #[serde(default)]
+    cadence: Option<Cadence>,
+    #[serde(default)]
+    kind: Option<Kind>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -32,12 +39,95 @@ struct SetWithRests {
     work: Set,
 }
 
+/// A routine as a tree, with repeats kept compact rather than expanded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RoutineNode {
+    Work {
+        name: String,
+        duration: u32,
+        kind: Option<Kind>,
+    },
+    Block {
+        name: String,
+        repeats: usize,
+        rest: u32,
+        children: Vec<RoutineNode>,
+    },
+}
+
+impl RoutineNode {
+    pub fn name(&self) -> &str {
+        match self {
+            RoutineNode::Work { name, .. } | RoutineNode::Block { name, .. } => name,
+        }
+    }
+    /// Number of rounds the children are laid out in. A plain set is one round
+    /// of each child in turn, a repeat is `repeats` rounds of its single child.
+    fn rounds(&self) -> usize {
+        match self {
+            RoutineNode::Work { .. } => 1,
+            RoutineNode::Block {
+                repeats, children, ..
+            } => repeats * children.len(),
+        }
+    }
+    /// Number of items this takes up in the flat list, rests included.
+    pub fn flat_len(&self) -> usize {
+        match self {
+            RoutineNode::Work { .. } => 1,
+            RoutineNode::Block { .. } => self
+                .laid_out(0)
+                .last()
+                .map_or(0, |(child, start)| start + child.flat_len()),
+        }
+    }
+    /// The children in the order they're run, one per round, each with the
+    /// index it starts at in the flat list if this starts at `start`.
+    pub fn laid_out(&self, start: usize) -> Vec<(&RoutineNode, usize)> {
+        let children = match self {
+            RoutineNode::Work { .. } => return Vec::new(),
+            RoutineNode::Block { children, .. } if children.is_empty() => return Vec::new(),
+            RoutineNode::Block { children, .. } => children,
+        };
+        let mut at = start;
+        (0..self.rounds())
+            .map(|k| {
+                let child = &children[k % children.len()];
+                let ans = (child, at);
+                // Then a rest before the next round.
+                at += child.flat_len() + 1;
+                ans
+            })
+            .collect()
+    }
+    /// Total time in seconds, including rests between rounds.
+    pub fn total_duration(&self) -> u32 {
+        match self {
+            RoutineNode::Work { duration, .. } => *duration,
+            RoutineNode::Block {
+                repeats,
+                rest,
+                children,
+                ..
+            } => {
+                let one: u32 = children.iter().map(|c| c.total_duration()).sum();
+                one * *repeats as u32 + rest * (self.rounds().max(1) as u32 - 1)
+            }
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Routine {
     definitions: HashMap<String, SetWithRests>,
     top: String,
+    #[serde(default)]
+    sounds: Option<SoundProfile>,
 }
 impl Routine {
+    pub fn sounds(&self) -> Option<&SoundProfile> {
+        self.sounds.as_ref()
+    }
     pub fn to_full_workout(&self) -> Result<Vec<FlatStatus>, String> {
         self.to_workout(&self.top)
     }
@@ -59,10 +149,8 @@ impl Routine {
             for (ix, w) in work_list.iter().enumerate() {
                 if ix > 0 {
                     ans.push(FlatStatus {
-                        name: "rest".to_owned(),
-                        duration: Some(lu.rest),
-                        this_rep: 1,
-                        total_reps: 1,
+                        kind: Some(Kind::Rest),
+                        ..FlatStatus::new("rest", lu.rest)
                     })
                 }
                 match w {
@@ -71,6 +159,9 @@ impl Routine {
                         duration: Some(sw.duration),
                         this_rep: (ix as u32) + 1,
                         total_reps: work_list.len() as u32,
+                        cues: sw.cues.clone(),
+                        cadence: sw.cadence,
+                        kind: sw.kind,
                     }),
                     Work::Ref(n) => {
                         let mut v = self.to_workout(n)?;
@@ -81,6 +172,53 @@ impl Routine {
         }
         Ok(ans)
     }
+    pub fn to_full_tree(&self) -> Result<RoutineNode, String> {
+        self.to_tree(&self.top, &mut Vec::new())
+    }
+    fn to_tree<'a>(
+        &'a self,
+        name: &'a str,
+        seen: &mut Vec<&'a str>,
+    ) -> Result<RoutineNode, String> {
+        if seen.contains(&name) {
+            return Err(format!("Workout item refers to itself: {}", name));
+        }
+        let lu = self
+            .definitions
+            .get(name)
+            .ok_or_else(|| format!("Unknown workout item: {}", name))?;
+        seen.push(name);
+        let (repeats, work_list) = match &lu.work {
+            Set::Set(list) => (1, list.iter().collect::<Vec<_>>()),
+            Set::Repeat(sr) => (sr.repeats, vec![&sr.work]),
+        };
+        let children = work_list
+            .into_iter()
+            .map(|w| match w {
+                Work::Simple(sw) => Ok(RoutineNode::Work {
+                    name: sw.name.to_owned(),
+                    duration: sw.duration,
+                    kind: sw.kind,
+                }),
+                Work::Ref(n) => self.to_tree(n, seen),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        seen.pop();
+        Ok(RoutineNode::Block {
+            name: name.to_owned(),
+            repeats,
+            rest: lu.rest,
+            children,
+        })
+    }
+}
+
+/// Compiles a routine written in Dhall, against our types.
+pub fn compile(config: &str) -> Result<Routine, String> {
+    let full = TYPES.to_owned() + config;
+    serde_dhall::from_str(&full)
+        .parse::<Routine>()
+        .map_err(|e| format!("{}", e))
 }
 
 pub fn mk7min() -> Routine {
@@ -88,6 +226,15 @@ pub fn mk7min() -> Routine {
     serde_dhall::from_str(&data).parse().unwrap()
 }
 
+/// A short fingerprint of a routine's source, stable across devices and
+/// builds (FNV-1a), for telling whether two sessions ran the same thing.
+pub fn config_hash(config: &str) -> String {
+    let hash = config.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
 pub const TYPES: &'static str = include_str!("../data/types.dhall");
 pub const SEVEN: &'static str = include_str!("../data/7min.dhall");
 
@@ -116,4 +263,75 @@ mod test {
         assert_eq!(parsed.definitions.len(), 3);
         assert_eq!(parsed.top, "all");
     }
+    fn simple(duration: u32, name: &str) -> Work {
+        Work::Simple(SimpleWork {
+            duration,
+            name: name.to_owned(),
+            cues: Vec::new(),
+            cadence: None,
+            kind: None,
+        })
+    }
+    fn joe() -> Routine {
+        let mut definitions = HashMap::new();
+        definitions.insert(
+            "set".to_owned(),
+            SetWithRests {
+                rest: 30,
+                work: Set::Repeat(SetRepeat {
+                    repeats: 10,
+                    work: simple(30, "Work"),
+                }),
+            },
+        );
+        definitions.insert(
+            "two_sets".to_owned(),
+            SetWithRests {
+                rest: 120,
+                work: Set::Repeat(SetRepeat {
+                    repeats: 2,
+                    work: Work::Ref("set".to_owned()),
+                }),
+            },
+        );
+        definitions.insert(
+            "all".to_owned(),
+            SetWithRests {
+                rest: 0,
+                work: Set::Set(vec![
+                    simple(300, "Warmup"),
+                    Work::Ref("two_sets".to_owned()),
+                    simple(300, "Stretches"),
+                ]),
+            },
+        );
+        Routine {
+            definitions,
+            top: "all".to_owned(),
+            sounds: None,
+        }
+    }
+    #[test]
+    pub fn tree_matches_flat() {
+        let r = joe();
+        let tree = r.to_full_tree().unwrap();
+        let flat = r.to_full_workout().unwrap();
+        assert_eq!(
+            tree.total_duration(),
+            flat.iter().map(|x| x.duration.unwrap_or(0)).sum::<u32>()
+        );
+        assert_eq!(tree.flat_len(), flat.len());
+        let rounds = tree.laid_out(0);
+        assert_eq!(
+            rounds
+                .iter()
+                .map(|(n, at)| (n.name(), *at))
+                .collect::<Vec<_>>(),
+            vec![("Warmup", 0), ("two_sets", 2), ("Stretches", 42)]
+        );
+        assert_eq!(flat[42].name, "Stretches");
+        let sets = rounds[1].0.laid_out(2);
+        assert_eq!(sets[1].1, 22);
+        assert_eq!(flat[21].duration, Some(120));
+    }
 }
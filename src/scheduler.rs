@@ -0,0 +1,83 @@
+//! Lining cues up ahead of time. The engine says what's coming; this works
+//! out what to queue, and what to call off as plans change.
+use crate::engine::Cue;
+
+/// How far ahead to queue cues, in millis. Long enough to see a background
+/// tab, whose timers may only run once a minute, through to its next go.
+pub const HORIZON: i64 = 70_000;
+
+/// A cue, and when it's due in millis since the epoch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Planned {
+    pub at: i64,
+    pub cue: Cue,
+}
+
+/// Which queued cues to call off, and which planned ones to add. Anything
+/// already due is left alone: it's playing, or has been.
+pub fn reconcile(queued: &[Planned], plan: &[Planned], now: i64) -> (Vec<Planned>, Vec<Planned>) {
+    let cancel = queued
+        .iter()
+        .filter(|q| q.at > now && !plan.contains(q))
+        .copied()
+        .collect();
+    let add = plan
+        .iter()
+        .filter(|p| p.at > now && !queued.contains(p))
+        .copied()
+        .collect();
+    (cancel, add)
+}
+
+/// The queued cue that `cue`, just noticed, refers to, if it was queued.
+/// It may have gone off a while ago if we're catching up.
+pub fn find_due(queued: &[Planned], cue: Cue, now: i64) -> Option<usize> {
+    queued
+        .iter()
+        .enumerate()
+        .filter(|(_, q)| q.cue == cue && q.at <= now && !stale(q, now))
+        .max_by_key(|(_, q)| q.at)
+        .map(|(i, _)| i)
+}
+
+/// Too long ago to be worth keeping track of.
+pub fn stale(p: &Planned, now: i64) -> bool {
+    now - p.at >= HORIZON
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(at: i64, cue: Cue) -> Planned {
+        Planned { at, cue }
+    }
+    #[test]
+    pub fn replans() {
+        let queued = vec![
+            at(900, Cue::Warning(1)),
+            at(1000, Cue::Enter(1)),
+            at(2000, Cue::Warning(1)),
+        ];
+        // Paused: call off everything still to come.
+        assert_eq!(
+            reconcile(&queued, &[], 950),
+            (queued[1..].to_vec(), Vec::new())
+        );
+        // Ten more seconds: the same cues, later.
+        let plan = vec![at(11_000, Cue::Enter(1)), at(12_000, Cue::Warning(1))];
+        assert_eq!(
+            reconcile(&queued, &plan, 950),
+            (queued[1..].to_vec(), plan.clone())
+        );
+        assert_eq!(reconcile(&plan, &plan, 950), (Vec::new(), Vec::new()));
+    }
+    #[test]
+    pub fn finds_what_went_off() {
+        let queued = vec![at(1000, Cue::Warning(1)), at(5000, Cue::Warning(1))];
+        assert_eq!(find_due(&queued, Cue::Warning(1), 1016), Some(0));
+        assert_eq!(find_due(&queued, Cue::Warning(1), 900), None);
+        assert_eq!(find_due(&queued, Cue::Warning(2), 1016), None);
+        assert_eq!(find_due(&queued, Cue::Warning(1), 6000), Some(1));
+    }
+}
@@ -0,0 +1,103 @@
+use miniz_oxide::{deflate::compress_to_vec, inflate::decompress_to_vec};
+
+/// A routine (and optionally a room to join) carried in a share link's fragment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SharedLink {
+    pub config: String,
+    pub room: Option<String>,
+}
+
+pub fn encode_config(config: &str) -> String {
+    let compressed = compress_to_vec(config.as_bytes(), 9);
+    base64::encode_config(&compressed, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode_config(data: &str) -> Result<String, String> {
+    let compressed = base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| format!("Bad share link: {}", e))?;
+    let bytes = decompress_to_vec(&compressed).map_err(|e| format!("Bad share link: {:?}", e))?;
+    String::from_utf8(bytes).map_err(|e| format!("Bad share link: {}", e))
+}
+
+/// Percent-encodes everything but letters, digits and `-._~`, so that a
+/// room name can't break up the fragment.
+pub fn encode_room(room: &str) -> String {
+    room.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn decode_room(data: &str) -> Result<String, String> {
+    let bad = || format!("Bad room in share link: {}", data);
+    let mut bytes = Vec::new();
+    let mut rest = data.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2).ok_or_else(bad)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| bad())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| bad())?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| bad())
+}
+
+impl SharedLink {
+    /// Fragment is `<config>` or `<config>/<room>`, the room percent-encoded.
+    pub fn to_fragment(&self) -> String {
+        let data = encode_config(&self.config);
+        match &self.room {
+            Some(r) => format!("{}/{}", data, encode_room(r)),
+            None => data,
+        }
+    }
+    pub fn from_fragment(fragment: &str) -> Result<Self, String> {
+        let mut parts = fragment.splitn(2, '/');
+        let config = decode_config(parts.next().unwrap_or_default())?;
+        let room = match parts.next().filter(|r| !r.is_empty()) {
+            Some(r) => Some(decode_room(r)?),
+            None => None,
+        };
+        Ok(Self { config, room })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn round_trip() {
+        let link = SharedLink {
+            config: crate::routine::SEVEN.to_owned(),
+            room: Some("ABCD".to_owned()),
+        };
+        let fragment = link.to_fragment();
+        assert!(fragment.len() < link.config.len());
+        assert_eq!(SharedLink::from_fragment(&fragment), Ok(link));
+    }
+    #[test]
+    pub fn awkward_rooms() {
+        let link = SharedLink {
+            config: "1".to_owned(),
+            room: Some("gym/2 #a 100% ü".to_owned()),
+        };
+        let fragment = link.to_fragment();
+        assert!(!fragment[fragment.find('/').unwrap() + 1..].contains(|c| "/# ".contains(c)));
+        assert_eq!(SharedLink::from_fragment(&fragment), Ok(link));
+        assert_eq!(decode_room("ABCD"), Ok("ABCD".to_owned()));
+        assert!(decode_room("50%").is_err());
+    }
+    #[test]
+    pub fn rejects_garbage() {
+        assert!(SharedLink::from_fragment("not*base64").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::routine::RoutineNode;
+use crate::workout::{timer, Kind};
+
+fn tick_boxes(n: usize) -> String {
+    vec!["☐"; n].join(" ")
+}
+
+fn block_summary(repeats: usize, rest: u32, children: usize) -> String {
+    let mut parts = Vec::new();
+    if repeats > 1 {
+        parts.push(format!("{} rounds", repeats));
+    }
+    let rest_count = if repeats > 1 { repeats } else { children };
+    if rest > 0 && rest_count > 1 {
+        parts.push(format!("{} rest between", timer(rest.into())));
+    }
+    parts.join(", ")
+}
+
+/// The kind of item, where it's been given and isn't plain work.
+fn kind_label(kind: Option<Kind>) -> String {
+    match kind {
+        Some(k) if k != Kind::Work => format!(" ({})", k.name()),
+        _ => String::new(),
+    }
+}
+
+pub fn to_markdown(tree: &RoutineNode) -> String {
+    let mut out = format!("Total time: {}\n\n", timer(tree.total_duration().into()));
+    markdown_node(tree, 1, &mut out);
+    out
+}
+
+fn markdown_node(node: &RoutineNode, depth: usize, out: &mut String) {
+    match node {
+        RoutineNode::Work {
+            name,
+            duration,
+            kind,
+        } => {
+            out.push_str(&format!(
+                "- {}{} — {}\n",
+                name,
+                kind_label(*kind),
+                timer((*duration).into())
+            ));
+        }
+        RoutineNode::Block {
+            name,
+            repeats,
+            rest,
+            children,
+        } => {
+            out.push_str(&format!(
+                "\n{} {} ({})\n\n",
+                "#".repeat(depth.min(6)),
+                name,
+                timer(node.total_duration().into())
+            ));
+            let summary = block_summary(*repeats, *rest, children.len());
+            if !summary.is_empty() {
+                out.push_str(&format!("{}\n\n", summary));
+            }
+            if *repeats > 1 {
+                out.push_str(&format!("Rounds: {}\n\n", tick_boxes(*repeats)));
+            }
+            for c in children {
+                markdown_node(c, depth + 1, out);
+            }
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const SHEET_STYLE: &str = "body{font-family:sans-serif;margin:1cm}\
+section{margin-left:1em;border-left:2px solid #ccc;padding-left:1em}\
+h1,h2,h3,h4,h5,h6{margin:0.6em 0 0.2em}\
+.summary{color:#555}\
+.ticks{font-size:1.4em;letter-spacing:0.3em}\
+li{display:flex;justify-content:space-between;max-width:20em}\
+@media print{body{margin:0}}";
+
+pub fn to_html(tree: &RoutineNode) -> String {
+    let mut body = String::new();
+    html_node(tree, 1, &mut body);
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head>\
+         <body><p>Total time: {}</p>{}</body></html>",
+        escape(tree.name()),
+        SHEET_STYLE,
+        timer(tree.total_duration().into()),
+        body
+    )
+}
+
+fn html_node(node: &RoutineNode, depth: usize, out: &mut String) {
+    match node {
+        RoutineNode::Work {
+            name,
+            duration,
+            kind,
+        } => {
+            out.push_str(&format!(
+                "<li><span>{}{}</span><span>{}</span></li>",
+                escape(name),
+                kind_label(*kind),
+                timer((*duration).into())
+            ));
+        }
+        RoutineNode::Block {
+            name,
+            repeats,
+            rest,
+            children,
+        } => {
+            let h = depth.min(6);
+            out.push_str(&format!(
+                "<section><h{}>{} ({})</h{}>",
+                h,
+                escape(name),
+                timer(node.total_duration().into()),
+                h
+            ));
+            let summary = block_summary(*repeats, *rest, children.len());
+            if !summary.is_empty() {
+                out.push_str(&format!("<p class=\"summary\">{}</p>", summary));
+            }
+            if *repeats > 1 {
+                out.push_str(&format!("<p class=\"ticks\">{}</p>", tick_boxes(*repeats)));
+            }
+            // Keep runs of plain work items together in one list.
+            let mut in_list = false;
+            for c in children {
+                let is_work = matches!(c, RoutineNode::Work { .. });
+                if is_work && !in_list {
+                    out.push_str("<ul>");
+                } else if !is_work && in_list {
+                    out.push_str("</ul>");
+                }
+                in_list = is_work;
+                html_node(c, depth + 1, out);
+            }
+            if in_list {
+                out.push_str("</ul>");
+            }
+            out.push_str("</section>");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> RoutineNode {
+        RoutineNode::Block {
+            name: "all".into(),
+            repeats: 1,
+            rest: 0,
+            children: vec![
+                RoutineNode::Work {
+                    name: "Warmup".into(),
+                    duration: 300,
+                    kind: Some(Kind::WarmUp),
+                },
+                RoutineNode::Block {
+                    name: "set".into(),
+                    repeats: 3,
+                    rest: 30,
+                    children: vec![RoutineNode::Work {
+                        name: "Push ups".into(),
+                        duration: 30,
+                        kind: Some(Kind::Work),
+                    }],
+                },
+            ],
+        }
+    }
+    #[test]
+    pub fn markdown_keeps_repeats_compact() {
+        let md = to_markdown(&sample());
+        assert!(md.starts_with("Total time: 7:30\n"));
+        assert!(md.contains("## set (2:30)\n\n3 rounds, 0:30 rest between\n\nRounds: ☐ ☐ ☐\n"));
+        assert_eq!(md.matches("Push ups").count(), 1);
+        assert!(md.contains("- Warmup (warm-up) — 5:00\n"));
+        assert!(md.contains("- Push ups — 0:30\n"));
+    }
+    #[test]
+    pub fn html_is_escaped() {
+        let tree = RoutineNode::Work {
+            name: "<b>".into(),
+            duration: 5,
+            kind: None,
+        };
+        let html = to_html(&tree);
+        assert!(html.contains("&lt;b&gt;"));
+        assert!(!html.contains("<b>"));
+    }
+}
@@ -0,0 +1,315 @@
+//! What the cues sound like. A routine can bring its own sounds, and a
+//! device can use its own instead (or just be quiet). Either can name audio
+//! samples to play instead of tones, which are only heard on devices that
+//! have them loaded.
+use crate::engine::Cue;
+use crate::synth::Instrument;
+use crate::workout::FlatStatus;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tone {
+    /// Hz, or 0 for a gap.
+    pub frequency: u32,
+    pub millis: u32,
+    #[serde(default)]
+    pub instrument: Instrument,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoundProfile {
+    /// Seconds before the end of an item to start ticking.
+    pub warning: u32,
+    /// Started, paused or resumed.
+    pub go: Vec<Tone>,
+    /// Each second of the warning, and of a scheduled start's countdown.
+    pub tick: Vec<Tone>,
+    pub work: Vec<Tone>,
+    pub rest: Vec<Tone>,
+    /// Each beat of an item's cadence.
+    pub beat: Vec<Tone>,
+    pub accent: Vec<Tone>,
+    pub muted: bool,
+    pub samples: Vec<SlotSample>,
+}
+
+/// Play the sample with this name for a slot, rather than its tones.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SlotSample {
+    pub slot: Slot,
+    pub name: String,
+}
+
+/// What to play for a cue: the sample, if this device has it, or else the
+/// tones.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sound {
+    pub sample: Option<String>,
+    pub tones: Vec<Tone>,
+}
+
+/// The patterns a profile is made of, for editing them one at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Slot {
+    Go,
+    Tick,
+    Work,
+    Rest,
+    Beat,
+    Accent,
+}
+pub const SLOTS: [(Slot, &str); 6] = [
+    (Slot::Go, "Start / pause"),
+    (Slot::Tick, "Countdown"),
+    (Slot::Work, "Work"),
+    (Slot::Rest, "Rest"),
+    (Slot::Beat, "Beat"),
+    (Slot::Accent, "Accented beat"),
+];
+
+fn struck(instrument: Instrument, frequency: u32, millis: u32) -> Vec<Tone> {
+    vec![Tone {
+        frequency,
+        millis,
+        instrument,
+    }]
+}
+fn tone(frequency: u32, millis: u32) -> Vec<Tone> {
+    struck(Instrument::Sine, frequency, millis)
+}
+
+impl Default for SoundProfile {
+    fn default() -> Self {
+        Self {
+            warning: 3,
+            go: tone(880, 100),
+            tick: tone(440, 100),
+            work: tone(880, 200),
+            rest: tone(440, 200),
+            beat: struck(Instrument::Woodblock, 1000, 60),
+            accent: struck(Instrument::Woodblock, 1500, 60),
+            muted: false,
+            samples: Vec::new(),
+        }
+    }
+}
+
+fn accented(item: &FlatStatus, beat: usize) -> bool {
+    item.cadence.map_or(false, |c| c.accented(beat))
+}
+
+impl SoundProfile {
+    pub fn slot(&self, slot: Slot) -> &[Tone] {
+        match slot {
+            Slot::Go => &self.go,
+            Slot::Tick => &self.tick,
+            Slot::Work => &self.work,
+            Slot::Rest => &self.rest,
+            Slot::Beat => &self.beat,
+            Slot::Accent => &self.accent,
+        }
+    }
+    pub fn slot_mut(&mut self, slot: Slot) -> &mut Vec<Tone> {
+        match slot {
+            Slot::Go => &mut self.go,
+            Slot::Tick => &mut self.tick,
+            Slot::Work => &mut self.work,
+            Slot::Rest => &mut self.rest,
+            Slot::Beat => &mut self.beat,
+            Slot::Accent => &mut self.accent,
+        }
+    }
+    /// What to play for `cue`. `item` is the one it's about: the one being
+    /// entered, or the one running out.
+    pub fn pattern<'a>(&'a self, cue: Cue, item: &'a FlatStatus) -> &'a [Tone] {
+        if self.muted {
+            return &[];
+        }
+        match cue {
+            Cue::Go => &self.go,
+            Cue::Warning(_) | Cue::Countdown(_) => &self.tick,
+            Cue::Enter(_) if item.is_rest() => &self.rest,
+            Cue::Enter(_) => &self.work,
+            Cue::Halfway(_) => &[],
+            Cue::Item(_, c) => match item.cues.get(c) {
+                Some(c) if !c.tones.is_empty() => &c.tones,
+                _ => &self.tick,
+            },
+            Cue::Beat(_, n) if accented(item, n) => &self.accent,
+            Cue::Beat(..) => &self.beat,
+        }
+    }
+    pub fn sample(&self, slot: Slot) -> Option<&str> {
+        self.samples
+            .iter()
+            .find(|s| s.slot == slot)
+            .map(|s| s.name.as_str())
+    }
+    pub fn set_sample(&mut self, slot: Slot, name: Option<String>) {
+        self.samples.retain(|s| s.slot != slot);
+        if let Some(name) = name {
+            self.samples.push(SlotSample { slot, name });
+        }
+    }
+    /// The sample to play for `cue` instead of its tones, if any. An item
+    /// cue's own tones win over the tick's sample.
+    fn sample_for<'a>(&'a self, cue: Cue, item: &'a FlatStatus) -> Option<&'a str> {
+        let slot = match cue {
+            Cue::Go => Slot::Go,
+            Cue::Warning(_) | Cue::Countdown(_) => Slot::Tick,
+            Cue::Enter(_) if item.is_rest() => Slot::Rest,
+            Cue::Enter(_) => Slot::Work,
+            Cue::Halfway(_) => return None,
+            Cue::Beat(_, n) if accented(item, n) => Slot::Accent,
+            Cue::Beat(..) => Slot::Beat,
+            Cue::Item(_, c) => match item.cues.get(c) {
+                Some(c) if c.sample.is_some() => return c.sample.as_deref(),
+                Some(c) if !c.tones.is_empty() => return None,
+                _ => Slot::Tick,
+            },
+        };
+        self.sample(slot)
+    }
+    pub fn sound(&self, cue: Cue, item: &FlatStatus) -> Sound {
+        let tones = self.pattern(cue, item).to_vec();
+        let sample = if self.muted {
+            None
+        } else {
+            self.sample_for(cue, item).map(str::to_owned)
+        };
+        Sound { sample, tones }
+    }
+}
+
+/// This device's own sound settings, which win over the routine's.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoundPrefs {
+    pub muted: bool,
+    /// From 0 to 1.
+    pub volume: f32,
+    /// Used instead of whatever the routine says.
+    pub profile: Option<SoundProfile>,
+}
+
+impl Default for SoundPrefs {
+    fn default() -> Self {
+        Self {
+            muted: false,
+            volume: 1.,
+            profile: None,
+        }
+    }
+}
+
+impl SoundPrefs {
+    pub fn resolve(&self, routine: Option<&SoundProfile>) -> SoundProfile {
+        let mut ans = self
+            .profile
+            .as_ref()
+            .or(routine)
+            .cloned()
+            .unwrap_or_default();
+        ans.muted |= self.muted;
+        ans
+    }
+}
+
+/// Patterns are written as `frequency x millis`, space separated, e.g.
+/// `880x100 0x50 880x100` for two short beeps. Anything but a plain beep
+/// goes on the end, as in `660x2000:bell`.
+pub fn parse_pattern(s: &str) -> Result<Vec<Tone>, String> {
+    s.split_whitespace()
+        .map(|t| {
+            let mut sound = t.splitn(2, ':');
+            let mut parts = sound.next().unwrap_or_default().splitn(2, 'x');
+            let mut num = || {
+                parts
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| format!("Expected frequency x millis, got {}", t))
+            };
+            let (frequency, millis) = (num()?, num()?);
+            let instrument = match sound.next() {
+                None => Instrument::Sine,
+                Some(i) => {
+                    Instrument::from_name(i).ok_or_else(|| format!("No instrument called {}", i))?
+                }
+            };
+            Ok(Tone {
+                frequency,
+                millis,
+                instrument,
+            })
+        })
+        .collect()
+}
+pub fn format_pattern(tones: &[Tone]) -> String {
+    tones
+        .iter()
+        .map(|t| match t.instrument {
+            Instrument::Sine => format!("{}x{}", t.frequency, t.millis),
+            i => format!("{}x{}:{}", t.frequency, t.millis, i.name()),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn device_wins() {
+        let mut routine = SoundProfile::default();
+        routine.warning = 10;
+        let mut prefs = SoundPrefs::default();
+        assert_eq!(prefs.resolve(Some(&routine)).warning, 10);
+        prefs.profile = Some(SoundProfile::default());
+        assert_eq!(prefs.resolve(Some(&routine)).warning, 3);
+        prefs.muted = true;
+        let item = FlatStatus::new("rest", 10);
+        assert!(prefs.resolve(None).pattern(Cue::Enter(0), &item).is_empty());
+    }
+    #[test]
+    pub fn samples() {
+        use crate::workout::{CueAt, ItemCue};
+        let mut profile = SoundProfile::default();
+        profile.set_sample(Slot::Tick, Some("click".to_owned()));
+        profile.set_sample(Slot::Rest, Some("whistle".to_owned()));
+        profile.set_sample(Slot::Rest, None);
+        let cue = |tones: Vec<Tone>, sample: Option<&str>| ItemCue {
+            tones,
+            sample: sample.map(str::to_owned),
+            ..ItemCue::tick(CueAt::Seconds(5))
+        };
+        let item = FlatStatus {
+            cues: vec![
+                cue(Vec::new(), None),
+                cue(tone(660, 50), None),
+                cue(Vec::new(), Some("bell")),
+            ],
+            ..FlatStatus::new("rest", 10)
+        };
+        let sample = |c| profile.sound(c, &item).sample;
+        assert_eq!(sample(Cue::Warning(2)).as_deref(), Some("click"));
+        assert_eq!(sample(Cue::Enter(0)), None);
+        assert_eq!(profile.sound(Cue::Enter(0), &item).tones, tone(440, 200));
+        assert_eq!(sample(Cue::Item(0, 0)).as_deref(), Some("click"));
+        assert_eq!(sample(Cue::Item(0, 1)), None);
+        assert_eq!(sample(Cue::Item(0, 2)).as_deref(), Some("bell"));
+    }
+    #[test]
+    pub fn patterns() {
+        let p = parse_pattern(" 880x100 0x50  440x200").unwrap();
+        assert_eq!(p.len(), 3);
+        assert_eq!(format_pattern(&p), "880x100 0x50 440x200");
+        assert!(parse_pattern("880").is_err());
+        assert!(parse_pattern("loud").is_err());
+        let p = parse_pattern("660x2000:bell 0x500").unwrap();
+        assert_eq!(p[0].instrument, Instrument::Bell);
+        assert_eq!(format_pattern(&p), "660x2000:bell 0x500");
+        assert!(parse_pattern("660x2000:kazoo").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! What to say out loud, and when. The saying itself is up to the caller.
+use crate::engine::Cue;
+use crate::workout::FlatStatus;
+use serde::{Deserialize, Serialize};
+
+/// Phrases, with `{name}`, `{duration}`, `{next}`, `{next_duration}` and
+/// `{n}` filled in where they make sense.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Templates {
+    pub work: String,
+    pub rest: String,
+    /// Each second of a countdown.
+    pub count: String,
+    pub halfway: String,
+    pub finished: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            work: "Go".to_owned(),
+            rest: "Rest. Next: {next}, {next_duration}".to_owned(),
+            count: "{n}".to_owned(),
+            halfway: "Halfway".to_owned(),
+            finished: "Finished".to_owned(),
+        }
+    }
+}
+
+/// Which template is which, for editing them one at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Slot {
+    Work,
+    Rest,
+    Count,
+    Halfway,
+    Finished,
+}
+pub const SLOTS: [(Slot, &str); 5] = [
+    (Slot::Work, "Work"),
+    (Slot::Rest, "Rest"),
+    (Slot::Count, "Countdown"),
+    (Slot::Halfway, "Halfway"),
+    (Slot::Finished, "Finished"),
+];
+
+impl Templates {
+    pub fn slot(&self, slot: Slot) -> &str {
+        match slot {
+            Slot::Work => &self.work,
+            Slot::Rest => &self.rest,
+            Slot::Count => &self.count,
+            Slot::Halfway => &self.halfway,
+            Slot::Finished => &self.finished,
+        }
+    }
+    pub fn slot_mut(&mut self, slot: Slot) -> &mut String {
+        match slot {
+            Slot::Work => &mut self.work,
+            Slot::Rest => &mut self.rest,
+            Slot::Count => &mut self.count,
+            Slot::Halfway => &mut self.halfway,
+            Slot::Finished => &mut self.finished,
+        }
+    }
+}
+
+/// This device's speech settings.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpeechPrefs {
+    pub enabled: bool,
+    /// BCP 47, like `en-GB`.
+    pub lang: String,
+    /// Name of the voice to use, out of those for `lang`.
+    pub voice: Option<String>,
+    pub templates: Templates,
+}
+
+impl Default for SpeechPrefs {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lang: "en-GB".to_owned(),
+            voice: None,
+            templates: Templates::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Utterance {
+    pub text: String,
+    /// Cut off anything still being said. Countdowns need to be on time.
+    pub interrupt: bool,
+    /// Said instead of the cue's tones, rather than after them.
+    pub replaces_tones: bool,
+}
+
+/// `90` is "1 minute 30 seconds".
+pub fn duration_words(secs: u32) -> String {
+    let plural = |n: u32, unit: &str| format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" });
+    match (secs / 60, secs % 60) {
+        (0, s) => plural(s, "second"),
+        (m, 0) => plural(m, "minute"),
+        (m, s) => format!("{} {}", plural(m, "minute"), plural(s, "second")),
+    }
+}
+
+fn fill(template: &str, item: Option<&FlatStatus>, next: Option<&FlatStatus>, n: i64) -> String {
+    let dur = |i: Option<&FlatStatus>| {
+        i.and_then(|i| i.duration)
+            .map(duration_words)
+            .unwrap_or_default()
+    };
+    let name = |i: Option<&FlatStatus>| i.map(|i| i.name.clone()).unwrap_or_default();
+    template
+        .replace("{name}", &name(item))
+        .replace("{duration}", &dur(item))
+        .replace("{next}", &name(next))
+        .replace("{next_duration}", &dur(next))
+        .replace("{n}", &n.to_string())
+}
+
+impl SpeechPrefs {
+    /// What to say for `cue`, if anything. `ix` is the current item.
+    pub fn phrase(&self, cue: Cue, items: &[FlatStatus], ix: usize) -> Option<Utterance> {
+        if !self.enabled {
+            return None;
+        }
+        let t = &self.templates;
+        let say = |template: &str, ix: usize, n: i64, interrupt: bool| {
+            let text = fill(template, items.get(ix), items.get(ix + 1), n);
+            Some(Utterance {
+                text,
+                interrupt,
+                replaces_tones: interrupt,
+            })
+            .filter(|u| !u.text.trim().is_empty())
+        };
+        match cue {
+            Cue::Go | Cue::Beat(..) => None,
+            Cue::Warning(n) | Cue::Countdown(n) => say(&t.count, ix, n, true),
+            Cue::Enter(i) if i >= items.len() => say(&t.finished, i, 0, false),
+            Cue::Enter(i) if items[i].is_rest() => say(&t.rest, i, 0, false),
+            Cue::Enter(i) => say(&t.work, i, 0, false),
+            Cue::Halfway(i) => say(&t.halfway, i, 0, false),
+            Cue::Item(i, c) => items
+                .get(i)
+                .and_then(|item| item.cues.get(c))
+                .and_then(|c| say(&c.label, i, 0, false)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn item(name: &str, duration: u32) -> FlatStatus {
+        FlatStatus::new(name, duration)
+    }
+    #[test]
+    pub fn phrases() {
+        let items = vec![item("Squat", 30), item("rest", 10), item("Push ups", 90)];
+        let mut prefs = SpeechPrefs::default();
+        assert_eq!(prefs.phrase(Cue::Enter(1), &items, 1), None);
+        prefs.enabled = true;
+        let text = |cue| prefs.phrase(cue, &items, 1).map(|u| u.text);
+        assert_eq!(
+            text(Cue::Enter(1)).as_deref(),
+            Some("Rest. Next: Push ups, 1 minute 30 seconds")
+        );
+        assert_eq!(text(Cue::Warning(3)).as_deref(), Some("3"));
+        assert_eq!(text(Cue::Enter(3)).as_deref(), Some("Finished"));
+        assert_eq!(text(Cue::Go), None);
+        assert!(prefs.phrase(Cue::Warning(2), &items, 1).unwrap().interrupt);
+    }
+    #[test]
+    pub fn durations() {
+        assert_eq!(duration_words(1), "1 second");
+        assert_eq!(duration_words(120), "2 minutes");
+        assert_eq!(duration_words(61), "1 minute 1 second");
+    }
+}
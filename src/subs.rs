@@ -2,4 +2,6 @@
 pub enum Event {
     Connect,
     Disconnect,
+    ShowHistory,
+    HideHistory,
 }
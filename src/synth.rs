@@ -0,0 +1,183 @@
+//! Struck and rung instruments, gentler than a bare sine beep, built from
+//! oscillators, gain envelopes and filters.
+use serde::{Deserialize, Serialize};
+use web_sys::{AudioContext, AudioNode, AudioScheduledSourceNode, BiquadFilterType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Instrument {
+    Sine,
+    Bell,
+    Gong,
+    Woodblock,
+    Chime,
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Instrument::Sine
+    }
+}
+
+pub const INSTRUMENTS: [(Instrument, &str); 5] = [
+    (Instrument::Sine, "sine"),
+    (Instrument::Bell, "bell"),
+    (Instrument::Gong, "gong"),
+    (Instrument::Woodblock, "woodblock"),
+    (Instrument::Chime, "chime"),
+];
+
+impl Instrument {
+    pub fn name(self) -> &'static str {
+        INSTRUMENTS.iter().find(|i| i.0 == self).map_or("", |i| i.1)
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        INSTRUMENTS.iter().find(|i| i.1 == name).map(|i| i.0)
+    }
+}
+
+/// One oscillator of an instrument's sound.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Partial {
+    /// Of the tone's frequency.
+    pub ratio: f64,
+    pub gain: f32,
+    /// Seconds to die away to about a third.
+    pub decay: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    /// Cut off this many times the tone's frequency.
+    Lowpass(f64),
+    /// Around this many times the tone's frequency.
+    Bandpass(f64),
+}
+
+pub struct Recipe {
+    pub partials: &'static [Partial],
+    pub filter: Option<Filter>,
+}
+
+const fn p(ratio: f64, gain: f32, decay: f64) -> Partial {
+    Partial { ratio, gain, decay }
+}
+
+const SINE: &[Partial] = &[p(1., 1., f64::INFINITY)];
+/// Inharmonic, like a church bell, the low hum ringing longest.
+const BELL: &[Partial] = &[
+    p(0.5, 0.2, 2.),
+    p(1., 0.3, 1.2),
+    p(1.2, 0.15, 0.8),
+    p(1.5, 0.1, 0.6),
+    p(2., 0.1, 0.5),
+    p(2.74, 0.08, 0.3),
+    p(3.76, 0.05, 0.2),
+];
+const GONG: &[Partial] = &[
+    p(1., 0.35, 3.),
+    p(1.48, 0.2, 2.5),
+    p(2.09, 0.15, 2.),
+    p(2.76, 0.1, 1.5),
+    p(3.6, 0.08, 1.),
+];
+const WOODBLOCK: &[Partial] = &[p(1., 0.6, 0.04), p(2.76, 0.25, 0.02), p(5.4, 0.1, 0.01)];
+/// Tubular, with a clear pitch.
+const CHIME: &[Partial] = &[
+    p(1., 0.4, 1.5),
+    p(2.76, 0.25, 0.8),
+    p(5.4, 0.15, 0.4),
+    p(8.93, 0.08, 0.2),
+];
+
+/// Sine is held for the length of the tone; the rest are struck at the
+/// start and left to ring, cut off at the end of the tone.
+pub fn recipe(instrument: Instrument) -> Recipe {
+    let (partials, filter) = match instrument {
+        Instrument::Sine => (SINE, None),
+        Instrument::Bell => (BELL, None),
+        Instrument::Gong => (GONG, Some(Filter::Lowpass(4.))),
+        Instrument::Woodblock => (WOODBLOCK, Some(Filter::Bandpass(1.))),
+        Instrument::Chime => (CHIME, None),
+    };
+    Recipe { partials, filter }
+}
+
+/// Seconds to fade in and out over, so that tones don't click.
+const ATTACK: f64 = 0.005;
+const RELEASE: f64 = 0.02;
+
+/// Play `instrument` at `frequency` Hz into `out`, from `at` for `duration`
+/// seconds on the audio clock.
+pub fn play(
+    ctx: &AudioContext,
+    out: &AudioNode,
+    instrument: Instrument,
+    frequency: f64,
+    at: f64,
+    duration: f64,
+) -> Option<Vec<AudioScheduledSourceNode>> {
+    let recipe = recipe(instrument);
+    let end = at + duration;
+    let out: AudioNode = match recipe.filter {
+        None => out.clone(),
+        Some(filter) => {
+            let f = ctx.create_biquad_filter().ok()?;
+            let (kind, ratio) = match filter {
+                Filter::Lowpass(r) => (BiquadFilterType::Lowpass, r),
+                Filter::Bandpass(r) => (BiquadFilterType::Bandpass, r),
+            };
+            f.set_type(kind);
+            f.frequency().set_value((frequency * ratio) as f32);
+            f.connect_with_audio_node(out).ok()?;
+            f.into()
+        }
+    };
+    let mut oscs = Vec::new();
+    for partial in recipe.partials {
+        let osc = ctx.create_oscillator().ok()?;
+        let gain = ctx.create_gain().ok()?;
+        osc.connect_with_audio_node(&gain).ok()?;
+        gain.connect_with_audio_node(&out).ok()?;
+        osc.frequency()
+            .set_value((frequency * partial.ratio) as f32);
+        let g = gain.gain();
+        g.set_value_at_time(0., at).ok()?;
+        g.linear_ramp_to_value_at_time(partial.gain, at + ATTACK)
+            .ok()?;
+        if partial.decay.is_finite() {
+            g.set_target_at_time(0., at + ATTACK, partial.decay).ok()?;
+        }
+        let release = (end - RELEASE).max(at + ATTACK);
+        g.set_target_at_time(0., release, RELEASE / 3.).ok()?;
+        oscs.push(osc);
+    }
+    // Everything is wired up before anything starts, and if one partial
+    // won't start the rest are stopped, rather than left sounding with
+    // nobody holding on to them.
+    for osc in &oscs {
+        if osc.start_with_when(at).is_err() || osc.stop_with_when(end).is_err() {
+            for o in &oscs {
+                o.stop().ok();
+            }
+            return None;
+        }
+    }
+    Some(oscs.into_iter().map(Into::into).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn recipes() {
+        for &(i, name) in INSTRUMENTS.iter() {
+            assert_eq!(Instrument::from_name(name), Some(i));
+            let r = recipe(i);
+            let loudest: f32 = r.partials.iter().map(|p| p.gain).sum();
+            assert!(loudest <= 1., "{} would clip", name);
+            assert!(r.partials.iter().all(|p| p.ratio > 0. && p.decay > 0.));
+        }
+        assert_eq!(Instrument::from_name("kazoo"), None);
+    }
+}
@@ -0,0 +1,111 @@
+//! A room can run several timers side by side, each with its own session
+//! and roles. Updates for all of them come in together; this sorts out what
+//! to make of each one.
+use crate::roles::Roster;
+
+/// The parts of an update that say which timer it's for, and whose it is.
+#[derive(Clone, Copy, Debug)]
+pub struct Header<'a> {
+    pub timer: &'a str,
+    /// By the room's clock.
+    pub updated: i64,
+    /// The role id of the sender.
+    pub from: &'a str,
+    pub roster: &'a Roster,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Route {
+    /// Not the sender's to send.
+    Refuse,
+    /// For a timer we're not following, and no older than what we last
+    /// heard of it.
+    Keep,
+    /// For a timer we're not following, but older than what we know.
+    Ignore,
+    /// For ours, but older than what we have: they're behind, probably just
+    /// back from a reload.
+    Behind,
+    Apply,
+}
+
+/// What to do with `incoming`, given `ours`, the timer we're following, and
+/// what we last heard of the update's timer if it's another one. Who may
+/// send what goes by the roles on the update's timer, as far as we know
+/// them.
+pub fn route(incoming: Header, ours: Header, known: Option<Header>) -> Route {
+    let following = incoming.timer == ours.timer;
+    let roster = if following {
+        Some(ours.roster)
+    } else {
+        known.map(|k| k.roster)
+    };
+    if !roster.map_or(true, |r| r.accepts(incoming.from, incoming.roster)) {
+        return Route::Refuse;
+    }
+    if following && incoming.updated < ours.updated {
+        Route::Behind
+    } else if following {
+        Route::Apply
+    } else if known.map_or(false, |k| k.updated > incoming.updated) {
+        Route::Ignore
+    } else {
+        Route::Keep
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header<'a>(timer: &'a str, updated: i64, from: &'a str, roster: &'a Roster) -> Header<'a> {
+        Header {
+            timer,
+            updated,
+            from,
+            roster,
+        }
+    }
+
+    #[test]
+    pub fn routes_by_timer() {
+        let open = Roster::default();
+        let ours = header("", 10, "me", &open);
+        assert_eq!(route(header("", 20, "tv", &open), ours, None), Route::Apply);
+        assert_eq!(route(header("", 5, "tv", &open), ours, None), Route::Behind);
+        let heard = header("b", 10, "tv", &open);
+        assert_eq!(route(heard, ours, None), Route::Keep);
+        assert_eq!(
+            route(header("b", 12, "tv", &open), ours, Some(heard)),
+            Route::Keep
+        );
+        assert_eq!(
+            route(header("b", 8, "tv", &open), ours, Some(heard)),
+            Route::Ignore
+        );
+    }
+    #[test]
+    pub fn roles_go_by_the_updates_timer() {
+        let open = Roster::default();
+        let mut owned = Roster::default();
+        owned.claim("laptop", 1);
+        // Only the owner runs our timer, but anyone may run another.
+        let ours = header("", 10, "me", &owned);
+        assert_eq!(
+            route(header("", 20, "tv", &owned), ours, None),
+            Route::Refuse
+        );
+        assert_eq!(
+            route(header("", 20, "laptop", &owned), ours, None),
+            Route::Apply
+        );
+        assert_eq!(route(header("b", 20, "tv", &open), ours, None), Route::Keep);
+        // Once we know another timer is owned, that holds there too.
+        let heard = header("b", 10, "laptop", &owned);
+        let from_tv = header("b", 20, "tv", &owned);
+        assert_eq!(
+            route(from_tv, header("", 10, "me", &open), Some(heard)),
+            Route::Refuse
+        );
+    }
+}
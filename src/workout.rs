@@ -1,3 +1,4 @@
+use crate::sound::Tone;
 use csv;
 use serde::Deserialize;
 
@@ -13,12 +14,144 @@ pub struct WorkoutItem {
     content: Work,
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Clone)]
+/// Where in an item a cue comes.
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub enum CueAt {
+    /// Seconds in.
+    Seconds(u32),
+    /// This far through: 0.5 is halfway.
+    Fraction(f64),
+    /// Seconds before the end.
+    FromEnd(u32),
+    /// Every so many seconds.
+    Every(u32),
+}
+
+/// A cue part way through an item, like "switch sides".
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub struct ItemCue {
+    pub at: CueAt,
+    pub label: String,
+    /// Empty for the usual countdown tick.
+    pub tones: Vec<Tone>,
+    /// Played instead of the tones where the device has it.
+    #[serde(default)]
+    pub sample: Option<String>,
+}
+
+#[cfg(test)]
+impl ItemCue {
+    /// The usual tick with no label, at `at`.
+    pub fn tick(at: CueAt) -> Self {
+        Self {
+            at,
+            label: String::new(),
+            tones: Vec::new(),
+            sample: None,
+        }
+    }
+}
+
+/// A steady beat to keep to through an item, like a metronome.
+#[derive(Debug, PartialEq, Deserialize, Clone, Copy)]
+pub struct Cadence {
+    pub bpm: u32,
+    /// Accent every this many beats, counting the start of the item as the
+    /// first; 0 for none.
+    pub accent: u32,
+}
+
+impl Cadence {
+    /// Millis into an item `duration` millis long of each beat, numbered
+    /// from the start of the item. The start itself is left out, as the
+    /// item's own cue marks it.
+    pub fn beats(&self, duration: i64) -> Vec<(usize, i64)> {
+        let bpm = i64::from(self.bpm);
+        if bpm == 0 {
+            return Vec::new();
+        }
+        (1..)
+            .map(|n| (n as usize, n * 60_000 / bpm))
+            .take_while(|&(_, o)| o < duration)
+            .collect()
+    }
+    pub fn accented(&self, beat: usize) -> bool {
+        self.accent > 0 && beat % self.accent as usize == 0
+    }
+}
+
+impl CueAt {
+    /// Millis into an item `duration` millis long, each with a number to
+    /// tell repeats apart. Cues right at the start or end are left out, as
+    /// moving between items has its own.
+    pub fn offsets(&self, duration: i64) -> Vec<(usize, i64)> {
+        let secs = |s: u32| i64::from(s) * 1000;
+        let all = match *self {
+            CueAt::Seconds(s) => vec![secs(s)],
+            CueAt::Fraction(f) => vec![(duration as f64 * f).round() as i64],
+            CueAt::FromEnd(s) => vec![duration - secs(s)],
+            CueAt::Every(0) => Vec::new(),
+            CueAt::Every(s) => (1..)
+                .map(|n| n * secs(s))
+                .take_while(|&o| o < duration)
+                .collect(),
+        };
+        all.into_iter()
+            .enumerate()
+            .filter(|&(_, o)| o > 0 && o < duration)
+            .collect()
+    }
+}
+
+/// What sort of item this is, which decides how it looks and sounds. The
+/// aliases are for CSV, where the Dhall constructor names would be awkward.
+#[derive(Debug, PartialEq, Eq, Deserialize, Clone, Copy)]
+pub enum Kind {
+    #[serde(alias = "work")]
+    Work,
+    #[serde(alias = "rest")]
+    Rest,
+    #[serde(alias = "prep")]
+    Prep,
+    #[serde(alias = "warm-up")]
+    WarmUp,
+    #[serde(alias = "cool-down")]
+    CoolDown,
+    #[serde(alias = "transition")]
+    Transition,
+}
+
+impl Kind {
+    /// Also its CSS class.
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Work => "work",
+            Kind::Rest => "rest",
+            Kind::Prep => "prep",
+            Kind::WarmUp => "warm-up",
+            Kind::CoolDown => "cool-down",
+            Kind::Transition => "transition",
+        }
+    }
+    /// A break from the work, cued and coloured as a rest.
+    pub fn is_rest(self) -> bool {
+        matches!(self, Kind::Rest | Kind::Prep | Kind::Transition)
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
 pub struct FlatStatus {
     pub name: String,
     pub this_rep: u32,
     pub total_reps: u32,
     pub duration: Option<u32>,
+    #[serde(default)]
+    pub cues: Vec<ItemCue>,
+    #[serde(default)]
+    pub cadence: Option<Cadence>,
+    /// None for routines written before there were kinds.
+    #[serde(default)]
+    pub kind: Option<Kind>,
 }
 
 pub fn timer(duration: i64) -> String {
@@ -30,10 +163,62 @@ pub fn timer(duration: i64) -> String {
     )
 }
 
+/// The next time the local wall clock reads `hh:mm`, in millis since the
+/// epoch. `tz_offset_minutes` is UTC minus local time, as JS's
+/// `Date.getTimezoneOffset` gives it.
+pub fn next_local_time(now: i64, hhmm: &str, tz_offset_minutes: i64) -> Option<i64> {
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+    let mut parts = hhmm.trim().splitn(2, ':');
+    let h: i64 = parts.next()?.parse().ok()?;
+    let m: i64 = parts.next()?.parse().ok()?;
+    if h > 23 || m > 59 {
+        return None;
+    }
+    let local_now = now - tz_offset_minutes * 60_000;
+    let mut target = local_now - local_now.rem_euclid(DAY) + (h * 60 + m) * 60_000;
+    if target <= local_now {
+        target += DAY;
+    }
+    Some(target + tz_offset_minutes * 60_000)
+}
+
 impl FlatStatus {
+    /// Done once, with no cues, and its kind left to the name.
+    pub fn new(name: &str, duration: u32) -> Self {
+        FlatStatus {
+            name: name.to_owned(),
+            this_rep: 1,
+            total_reps: 1,
+            duration: Some(duration),
+            cues: Vec::new(),
+            cadence: None,
+            kind: None,
+        }
+    }
+    /// As given, or else guessed from the name. Only END itself is a rest,
+    /// not everything starting "end", like an endurance run.
+    pub fn kind(&self) -> Kind {
+        self.kind.unwrap_or_else(|| {
+            let lc = self.name.to_ascii_lowercase();
+            if lc.starts_with("rest") || lc.starts_with("recover") || lc == "end" {
+                Kind::Rest
+            } else {
+                Kind::Work
+            }
+        })
+    }
     pub fn is_rest(&self) -> bool {
-        let lc = self.name.to_ascii_lowercase();
-        lc.starts_with("rest") || lc.starts_with("recover") || lc.starts_with("end")
+        self.kind().is_rest()
+    }
+    /// The rest or work class, and the kind's own for anything else.
+    pub fn classes(&self) -> Vec<&'static str> {
+        let kind = self.kind();
+        let base = if kind.is_rest() { "rest" } else { "work" };
+        if kind.name() == base {
+            vec![base]
+        } else {
+            vec![base, kind.name()]
+        }
     }
     pub fn rep_str(&self) -> String {
         if self.total_reps > 1 {
@@ -73,25 +258,21 @@ impl WorkoutItem {
     }
     pub fn describe<'a>(&'a self) -> Vec<FlatStatus> {
         let mut ans = Vec::new();
-        let mut add = |name: &str, duration: Option<u32>, this_rep, total_reps| {
-            ans.push(FlatStatus {
-                name: name.to_string(),
-                duration,
-                this_rep,
-                total_reps,
-            });
-        };
         for rep in 0..self.reps {
             if rep > 0 && self.rest_between > 0 {
-                add("Rest", Some(self.rest_between), 1, 1);
+                ans.push(FlatStatus {
+                    kind: Some(Kind::Rest),
+                    ..FlatStatus::new("Rest", self.rest_between)
+                });
             }
             match &self.content {
-                Work::Seconds(x) => add(&format!("{}", self.name), Some(*x), rep + 1, self.reps),
-                Work::Composite(v) => {
-                    for x in v.iter().map(|x| x.describe()).flatten() {
-                        add(&x.name, x.duration, x.this_rep, x.total_reps);
-                    }
-                }
+                Work::Seconds(x) => ans.push(FlatStatus {
+                    this_rep: rep + 1,
+                    total_reps: self.reps,
+                    kind: Some(Kind::Work),
+                    ..FlatStatus::new(&self.name, *x)
+                }),
+                Work::Composite(v) => ans.extend(v.iter().flat_map(|x| x.describe())),
             }
         }
         ans
@@ -139,6 +320,55 @@ mod test {
         assert_eq!(joe_wicks().describe(), Vec::<FlatStatus>::new());
     }
     #[test]
+    pub fn local_times() {
+        // 2020-08-01 10:00 UTC, which is 11:00 in UTC+1.
+        let now = 1_596_276_000_000;
+        assert_eq!(next_local_time(now, "11:30", -60), Some(now + 30 * 60_000));
+        assert_eq!(
+            next_local_time(now, "10:59", -60),
+            Some(now + (24 * 60 - 1) * 60_000)
+        );
+        assert_eq!(next_local_time(now, "25:00", 0), None);
+    }
+    #[test]
+    pub fn cue_offsets() {
+        assert_eq!(CueAt::Fraction(0.5).offsets(60_000), vec![(0, 30_000)]);
+        assert_eq!(CueAt::FromEnd(10).offsets(60_000), vec![(0, 50_000)]);
+        assert_eq!(CueAt::FromEnd(90).offsets(60_000), vec![]);
+        assert_eq!(CueAt::Every(4).offsets(12_000), vec![(0, 4000), (1, 8000)]);
+    }
+    #[test]
+    pub fn cadence_beats() {
+        let c = Cadence { bpm: 90, accent: 3 };
+        assert_eq!(
+            c.beats(3000),
+            vec![(1, 666), (2, 1333), (3, 2000), (4, 2666)]
+        );
+        assert!(c.accented(3) && !c.accented(4));
+        assert!(!Cadence { bpm: 90, accent: 0 }.accented(3));
+        assert!(Cadence { bpm: 0, accent: 0 }.beats(3000).is_empty());
+    }
+    #[test]
+    pub fn kinds() {
+        let csv = "name,this_rep,total_reps,duration,kind\n\
+                   Descanso,1,1,30,rest\n\
+                   Restorative stretch,1,1,60,cool-down\n\
+                   Rest,1,1,10,\n\
+                   Squats,1,1,30,Work\n";
+        let items = FlatStatus::from_csv(csv).unwrap();
+        let kinds: Vec<_> = items.iter().map(|i| i.kind()).collect();
+        assert_eq!(
+            kinds,
+            vec![Kind::Rest, Kind::CoolDown, Kind::Rest, Kind::Work]
+        );
+        assert!(items[0].is_rest() && !items[1].is_rest());
+        assert_eq!(items[1].classes(), vec!["work", "cool-down"]);
+        assert_eq!(items[3].classes(), vec!["work"]);
+        assert_eq!(FlatStatus::new("Endurance run", 600).kind(), Kind::Work);
+        assert_eq!(FlatStatus::new("END", 0).kind(), Kind::Rest);
+        assert_eq!(Kind::WarmUp.name(), "warm-up");
+    }
+    #[test]
     pub fn joe_duration() {
         assert_eq!(joe_wicks().total_duration(), 31 * 60);
     }